@@ -0,0 +1,134 @@
+import time
+import base64
+import asyncio
+import yaml
+from urllib.parse import urlsplit
+from settings import (AUTH_TYPE, AUTH_TOKEN, AUTH_USERNAME, AUTH_PASSWORD, AUTH_CREDENTIALS_FILE, AUTH_TOKEN_URL, AUTH_SIGNED_URLS, AUTH_HOSTS,
+                      SERVER_URL, FILELIST_URL, MANIFEST_SIGNATURE_URL, TIMESTAMP_URL, MIRRORS)
+from logger import log_info, log_error, log_debug
+from downloader.session import proxy_for_url
+
+# Tokens and signed URLs are renewed this many seconds before they expire, so a
+# request is never started with a credential that is about to run out.
+EXPIRY_MARGIN = 30
+
+def load_credentials():
+    credentials = {'token': AUTH_TOKEN, 'username': AUTH_USERNAME, 'password': AUTH_PASSWORD}
+    if AUTH_CREDENTIALS_FILE:
+        try:
+            with open(AUTH_CREDENTIALS_FILE, 'r') as file:
+                stored = yaml.safe_load(file) or {}
+            credentials.update({key: value for key, value in stored.items() if key in credentials and value})
+        except (OSError, yaml.YAMLError) as e:
+            log_error(f"Error reading credentials file {AUTH_CREDENTIALS_FILE}: {e}")
+            raise Exception(f"Error reading credentials file {AUTH_CREDENTIALS_FILE}: {e}")
+    return credentials
+
+def static_headers(credentials, scheme=None):
+    if credentials.get('token') and scheme in (None, 'bearer'):
+        return {'Authorization': f"Bearer {credentials['token']}"}
+    if credentials.get('username') and scheme in (None, 'basic'):
+        user_pass = f"{credentials['username']}:{credentials.get('password') or ''}".encode()
+        return {'Authorization': f"Basic {base64.b64encode(user_pass).decode()}"}
+    return {}
+
+def auth_hosts():
+    # Mirrors listed by the manifest are not trusted with credentials: only the
+    # hosts named in config are.
+    if AUTH_HOSTS:
+        return {host.lower() for host in AUTH_HOSTS}
+    urls = [SERVER_URL, FILELIST_URL, MANIFEST_SIGNATURE_URL, TIMESTAMP_URL] + [mirror['url'] for mirror in MIRRORS]
+    return {urlsplit(url).hostname.lower() for url in urls if url and urlsplit(url).hostname}
+
+class AuthProvider:
+    # authorize() returns the URL and headers to send; refresh() is called after
+    # a 401/403 with the credential that was rejected and returns True when a
+    # retry with fresh credentials is worth it.
+    def __init__(self):
+        self.hosts = auth_hosts()
+
+    def allows(self, url):
+        return (urlsplit(url).hostname or '').lower() in self.hosts
+
+    async def authorize(self, session, method, url, headers):
+        return url, headers, None
+
+    async def refresh(self, url, credential):
+        return False
+
+class StaticAuth(AuthProvider):
+    def __init__(self, credentials, scheme):
+        super().__init__()
+        self.headers = static_headers(credentials, scheme)
+        if not self.headers:
+            raise Exception(f"AUTH_TYPE is {scheme} but no matching credentials are configured")
+
+    async def authorize(self, session, method, url, headers):
+        if not self.allows(url):
+            return url, headers, None
+        return url, {**headers, **self.headers}, None
+
+class TokenEndpointAuth(AuthProvider):
+    # Fetches short-lived bearer tokens (or, with AUTH_SIGNED_URLS, one pre-signed
+    # URL per file and method) from AUTH_TOKEN_URL. The endpoint is called with
+    # the static credentials and must answer with JSON such as
+    # {"token": "...", "expires_in": 300} or {"url": "...", "expires_in": 300}.
+    def __init__(self, credentials, token_url=AUTH_TOKEN_URL, signed_urls=AUTH_SIGNED_URLS):
+        super().__init__()
+        self.client_headers = static_headers(credentials)
+        self.token_url = token_url
+        self.signed_urls = signed_urls
+        self.cache = {}
+        self.lock = asyncio.Lock()
+
+    def cache_key(self, method, url):
+        return (method, url) if self.signed_urls else None
+
+    async def fetch(self, session, method, url):
+        payload = {'file': url, 'method': method} if self.signed_urls else {}
+        async with session.post(self.token_url, json=payload, headers=self.client_headers, proxy=proxy_for_url(self.token_url)) as response:
+            response.raise_for_status()
+            data = await response.json(content_type=None)
+        credential = data.get('url') if self.signed_urls else data.get('token') or data.get('access_token')
+        if not credential:
+            raise ValueError(f"Token endpoint {self.token_url} returned no credential")
+        expires_at = time.monotonic() + float(data.get('expires_in', 300)) - EXPIRY_MARGIN
+        log_debug(f'Obtained {"signed URL" if self.signed_urls else "access token"} from {self.token_url}')
+        return credential, expires_at
+
+    async def authorize(self, session, method, url, headers):
+        if not self.allows(url):
+            return url, headers, None
+        key = self.cache_key(method, url)
+        async with self.lock:
+            cached = self.cache.get(key)
+            if cached is None or cached[1] <= time.monotonic():
+                cached = await self.fetch(session, method, url)
+                self.cache[key] = cached
+        credential = cached[0]
+        if self.signed_urls:
+            return credential, headers, credential
+        return url, {**headers, 'Authorization': f'Bearer {credential}'}, credential
+
+    async def refresh(self, url, credential):
+        if credential is None:
+            return False
+        async with self.lock:
+            for key, cached in list(self.cache.items()):
+                if cached[0] == credential:
+                    del self.cache[key]
+        log_info(f'Credential rejected for {url}, requesting a new one')
+        return True
+
+def create_auth_provider():
+    auth_type = (AUTH_TYPE or 'none').lower()
+    if auth_type == 'none':
+        return AuthProvider()
+    credentials = load_credentials()
+    if auth_type in ('bearer', 'basic'):
+        return StaticAuth(credentials, auth_type)
+    if auth_type == 'token_endpoint':
+        if not AUTH_TOKEN_URL:
+            raise Exception("AUTH_TYPE is token_endpoint but AUTH_TOKEN_URL is not set")
+        return TokenEndpointAuth(credentials)
+    raise Exception(f"Unknown AUTH_TYPE: {AUTH_TYPE}")
@@ -0,0 +1,103 @@
+import os
+import json
+import time
+from settings import TARGET_FOLDER, BACKUP_RETENTION_COUNT, BACKUP_RETENTION_SIZE
+from logger import log_info, log_error
+from downloader.file_manager import STATE_DIR_NAME, get_file_hash, fsync_path
+
+class BackupStore:
+    # Keeps the files replaced by the last BACKUP_RETENTION_COUNT updates. File
+    # contents live once per SHA-256 under objects/, and versions.json lists, for
+    # every update, what each touched file looked like before it (its hash, or
+    # None if the update created it).
+    def __init__(self, target_folder=TARGET_FOLDER, retention_count=BACKUP_RETENTION_COUNT, retention_size=BACKUP_RETENTION_SIZE):
+        self.root = os.path.join(target_folder, STATE_DIR_NAME, 'backups')
+        self.objects_dir = os.path.join(self.root, 'objects')
+        self.index_path = os.path.join(self.root, 'versions.json')
+        self.retention_count = retention_count
+        self.retention_size = retention_size * 1048576 if retention_size else 0
+
+    def object_path(self, file_hash):
+        return os.path.join(self.objects_dir, file_hash[:2], file_hash)
+
+    def load_versions(self):
+        try:
+            with open(self.index_path, 'r') as f:
+                return json.load(f)
+        except FileNotFoundError:
+            return []
+        except (OSError, ValueError) as e:
+            log_error(f"Error reading backup index {self.index_path}: {e}")
+            raise Exception(f"Error reading backup index {self.index_path}: {e}")
+
+    def save_versions(self, versions):
+        os.makedirs(self.root, exist_ok=True)
+        temp_path = f"{self.index_path}.tmp"
+        with open(temp_path, 'w') as f:
+            json.dump(versions, f, indent=1)
+            f.flush()
+            os.fsync(f.fileno())
+        os.replace(temp_path, self.index_path)
+        fsync_path(self.root, directory=True)
+
+    async def add_version(self, entries, backup_dir, label):
+        # entries are transaction journal entries; the previous copies of the
+        # files they replaced are waiting in backup_dir and get moved into the
+        # store, skipping contents it already holds.
+        files = {}
+        for entry in entries:
+            if not entry['existed']:
+                files[entry['name']] = None
+                continue
+            backup_path = os.path.join(backup_dir, entry['name'])
+            file_hash = entry.get('hash')
+            if os.path.exists(backup_path):
+                file_hash = file_hash or await get_file_hash(backup_path)
+                object_path = self.object_path(file_hash)
+                if os.path.exists(object_path):
+                    os.remove(backup_path)
+                else:
+                    os.makedirs(os.path.dirname(object_path), exist_ok=True)
+                    os.replace(backup_path, object_path)
+            if file_hash and os.path.exists(self.object_path(file_hash)):
+                files[entry['name']] = file_hash
+            else:
+                log_error(f'No backup could be kept for {entry["name"]}')
+
+        versions = self.load_versions()
+        version_id = max((version['id'] for version in versions), default=0) + 1
+        versions.append({'id': version_id, 'created': time.time(), 'label': label, 'files': files})
+        self.save_versions(self.prune(versions))
+        log_info(f'Backup version {version_id} saved ({len(files)} file(s))')
+        return version_id
+
+    def prune(self, versions):
+        # Oldest versions go first: restoring a version replays every newer one,
+        # so the retained history must stay contiguous up to the latest.
+        while versions and self.retention_count and len(versions) > self.retention_count:
+            versions = versions[1:]
+        while len(versions) > 1 and self.retention_size and self.referenced_size(versions) > self.retention_size:
+            versions = versions[1:]
+        referenced = {file_hash for version in versions for file_hash in version['files'].values() if file_hash}
+        for root, _, files in os.walk(self.objects_dir):
+            for file_hash in files:
+                if file_hash not in referenced:
+                    os.remove(os.path.join(root, file_hash))
+        return versions
+
+    def referenced_size(self, versions):
+        referenced = {file_hash for version in versions for file_hash in version['files'].values() if file_hash}
+        return sum(os.path.getsize(self.object_path(file_hash)) for file_hash in referenced if os.path.exists(self.object_path(file_hash)))
+
+    def restore_plan(self, version_id):
+        # State of every file touched since version_id was taken, as it was before
+        # that update: {name: hash} or {name: None} for files to delete.
+        versions = self.load_versions()
+        if not any(version['id'] == version_id for version in versions):
+            raise Exception(f"Backup version {version_id} does not exist")
+        plan = {}
+        for version in reversed(versions):
+            plan.update(version['files'])
+            if version['id'] == version_id:
+                break
+        return plan
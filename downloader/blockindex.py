@@ -0,0 +1,111 @@
+import os
+import struct
+from downloader.blocks import weak_checksum, strong_checksum, scan_for_blocks
+
+# A block index describes a published file as fixed-size blocks, each with a
+# weak rolling checksum and a strong hash, so a client can find the blocks it
+# already has anywhere in its local copy and fetch only the rest:
+#   magic, <block_size:u32> <file_size:u64>, then per block <weak:u32> <strong:16 bytes>
+BLOCK_INDEX_MAGIC = b'PATCHER-BLOCKS1\n'
+BLOCK_INDEX_SUFFIX = '.blocks'
+BLOCK_INDEX_BLOCK_SIZE = 16384
+BLOCK_RECORD = struct.Struct('>I16s')
+# The rolling scan costs about two microseconds per byte that matches nothing.
+# After this many unmatched bytes in a row it jumps ahead and only probes the
+# local copy, doubling the jump up to SEED_MAX_SKIP while nothing matches, and
+# goes back to a full scan as soon as a probe finds a block again.
+SEED_MAX_UNMATCHED = 2097152
+SEED_MAX_SKIP = 67108864
+
+class InvalidBlockIndex(Exception):
+    pass
+
+def write_block_index(file_path, index_path, block_size=BLOCK_INDEX_BLOCK_SIZE):
+    with open(file_path, 'rb') as source, open(index_path, 'wb') as output:
+        output.write(BLOCK_INDEX_MAGIC)
+        header = output.tell()
+        output.write(struct.pack('>IQ', block_size, 0))
+        file_size = 0
+        while True:
+            block = source.read(block_size)
+            if not block:
+                break
+            output.write(BLOCK_RECORD.pack(weak_checksum(block), strong_checksum(block)))
+            file_size += len(block)
+        output.seek(header)
+        output.write(struct.pack('>IQ', block_size, file_size))
+
+def read_block_index(index_path):
+    with open(index_path, 'rb') as f:
+        if f.read(len(BLOCK_INDEX_MAGIC)) != BLOCK_INDEX_MAGIC:
+            raise InvalidBlockIndex("Not a patcher block index")
+        block_size, file_size = struct.unpack('>IQ', f.read(12))
+        data = f.read()
+    if block_size == 0 or len(data) != -(-file_size // block_size) * BLOCK_RECORD.size:
+        raise InvalidBlockIndex("Block index is truncated")
+    blocks = [BLOCK_RECORD.unpack_from(data, offset) for offset in range(0, len(data), BLOCK_RECORD.size)]
+    return block_size, file_size, blocks
+
+def seed_from_local_file(local_path, index_path, destination, should_stop=None):
+    # Copies every block of the new file that already exists in local_path into
+    # destination (preallocated to the new size) and returns the byte ranges
+    # filled, as sorted (start, end) pairs with end exclusive. should_stop is
+    # polled between steps; once it returns true the blocks found so far are kept.
+    block_size, file_size, blocks = read_block_index(index_path)
+    lookup = {}
+    for number, (weak, strong) in enumerate(blocks):
+        lookup.setdefault(weak, {}).setdefault(strong, []).append(number)
+
+    def find(checksum, block):
+        candidates = lookup.get(checksum)
+        return candidates.get(strong_checksum(block)) if candidates else None
+
+    present = set()
+
+    def copy_block(numbers, block):
+        for number in numbers:
+            if number not in present:
+                output.seek(number * block_size)
+                output.write(block)
+                present.add(number)
+
+    with open(local_path, 'rb') as local, open(destination, 'r+b') as output:
+        local_size = os.fstat(local.fileno()).st_size
+        position = 0
+        skip = SEED_MAX_UNMATCHED
+        probing = False
+        while position < local_size and not (should_stop and should_stop()):
+            # A probe rolls over two blocks' worth of offsets, enough to find a
+            # block that has been shifted by any amount.
+            local.seek(position)
+            unmatched = 0
+            gave_up = False
+            for item in scan_for_blocks(local, block_size, find, block_size if probing else 1048576):
+                if item[0] == 'match':
+                    copy_block(item[1], item[2])
+                    position += block_size
+                    unmatched = 0
+                    probing = False
+                    skip = SEED_MAX_UNMATCHED
+                else:
+                    position += len(item[1])
+                    unmatched += len(item[1])
+                if should_stop and should_stop():
+                    break
+                if unmatched >= (2 * block_size if probing else SEED_MAX_UNMATCHED):
+                    gave_up = True
+                    break
+            if not gave_up:
+                break
+            position += skip
+            skip = min(skip * 2, SEED_MAX_SKIP)
+            probing = True
+
+    ranges = []
+    for number in sorted(present):
+        start, end = number * block_size, min((number + 1) * block_size, file_size)
+        if ranges and ranges[-1][1] == start:
+            ranges[-1] = (ranges[-1][0], end)
+        else:
+            ranges.append((start, end))
+    return ranges
@@ -0,0 +1,82 @@
+import zlib
+import hashlib
+
+# Block matching shared by delta generation and block-index updates: a cheap
+# rolling checksum (Adler-32) finds candidate blocks at any byte offset, and a
+# strong hash confirms them.
+ADLER_MOD = 65521
+
+def weak_checksum(data):
+    return zlib.adler32(data)
+
+def roll_checksum(checksum, out_byte, in_byte, block_size):
+    # Slides the Adler-32 window one byte: drops out_byte, appends in_byte.
+    a = checksum & 0xffff
+    b = checksum >> 16
+    a = (a - out_byte + in_byte) % ADLER_MOD
+    b = (b - block_size * out_byte + a - 1) % ADLER_MOD
+    return (b << 16) | a
+
+def strong_checksum(data):
+    return hashlib.sha256(data).digest()[:16]
+
+def build_block_index(path, block_size):
+    # {weak checksum: {strong checksum: offset}} of every full block in path.
+    index = {}
+    with open(path, 'rb') as f:
+        offset = 0
+        while True:
+            block = f.read(block_size)
+            if len(block) < block_size:
+                break
+            index.setdefault(weak_checksum(block), {}).setdefault(strong_checksum(block), offset)
+            offset += block_size
+    return index
+
+def find_block(index, checksum, block):
+    candidates = index.get(checksum)
+    if not candidates:
+        return None
+    return candidates.get(strong_checksum(block))
+
+def scan_for_blocks(stream, block_size, lookup, read_size=1048576):
+    # Walks stream and yields ('match', value, block) for every block lookup
+    # recognizes and ('data', bytes) for the bytes in between. lookup gets the
+    # weak checksum and the block and returns a value, or None for no match.
+    buffer = b''
+    position = 0
+    checksum = None
+    literal = bytearray()
+    end_of_stream = False
+    while True:
+        if len(buffer) - position < block_size + 1 and not end_of_stream:
+            chunk = stream.read(read_size)
+            end_of_stream = not chunk
+            buffer = buffer[position:] + chunk
+            position = 0
+        if len(buffer) - position < block_size:
+            literal += buffer[position:]
+            break
+        block = buffer[position:position + block_size]
+        if checksum is None:
+            checksum = weak_checksum(block)
+        value = lookup(checksum, block)
+        if value is not None:
+            if literal:
+                yield ('data', bytes(literal))
+                literal = bytearray()
+            yield ('match', value, block)
+            position += block_size
+            checksum = None
+            continue
+        literal.append(buffer[position])
+        if position + block_size < len(buffer):
+            checksum = roll_checksum(checksum, buffer[position], buffer[position + block_size], block_size)
+        else:
+            checksum = None
+        position += 1
+        if len(literal) >= read_size:
+            yield ('data', bytes(literal))
+            literal = bytearray()
+    if literal:
+        yield ('data', bytes(literal))
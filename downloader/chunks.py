@@ -0,0 +1,130 @@
+import os
+import re
+import asyncio
+import hashlib
+from logger import log_info, log_error
+from downloader.file_manager import fsync_path
+
+# Content-defined chunking: a chunk ends where a rolling gear hash of the last
+# bytes matches CHUNK_MASK, so an insertion only changes the chunks around it
+# and identical data yields identical chunks in any file or release.
+CHUNK_MIN_SIZE = 16384
+CHUNK_MAX_SIZE = 262144
+CHUNK_MASK = (1 << 16) - 1  # about 64 KB chunks on average
+CHUNK_DIR = 'chunks'
+GEAR = [int.from_bytes(hashlib.sha256(bytes([value])).digest()[:8], 'big') for value in range(256)]
+
+def is_chunk_hash(value):
+    # Chunk hashes become file names, so nothing but a SHA-256 hex digest is allowed.
+    return isinstance(value, str) and re.fullmatch('[0-9a-f]{64}', value) is not None
+
+def chunk_name(chunk_hash):
+    if not is_chunk_hash(chunk_hash):
+        raise ValueError(f"Invalid chunk hash {chunk_hash!r}")
+    return f'{CHUNK_DIR}/{chunk_hash[:2]}/{chunk_hash}'
+
+def split_into_chunks(stream, read_size=4194304):
+    # Yields the chunks of stream; each is at least CHUNK_MIN_SIZE bytes (except
+    # the last) and at most CHUNK_MAX_SIZE.
+    buffer = b''
+    start = 0
+    while True:
+        data = stream.read(read_size)
+        buffer = buffer[start:] + data
+        start = 0
+        while len(buffer) - start >= CHUNK_MAX_SIZE or (not data and start < len(buffer)):
+            end = find_chunk_end(buffer, start)
+            yield buffer[start:end]
+            start = end
+        if not data:
+            return
+
+def find_chunk_end(buffer, start):
+    limit = min(len(buffer), start + CHUNK_MAX_SIZE)
+    if limit <= start + CHUNK_MIN_SIZE:
+        return limit
+    gear = GEAR
+    value = 0
+    for position in range(start + CHUNK_MIN_SIZE - 64, limit):
+        value = ((value << 1) + gear[buffer[position]]) & 0xffffffffffffffff
+        if position >= start + CHUNK_MIN_SIZE and not value & CHUNK_MASK:
+            return position + 1
+    return limit
+
+def store_chunks(file_path, store_folder):
+    # Writes the chunks of file_path into store_folder (skipping those already
+    # there) and returns the file's [hash, size] chunk list.
+    chunks = []
+    with open(file_path, 'rb') as f:
+        for chunk in split_into_chunks(f):
+            chunk_hash = hashlib.sha256(chunk).hexdigest()
+            chunk_path = os.path.join(store_folder, chunk_name(chunk_hash))
+            if not os.path.exists(chunk_path):
+                os.makedirs(os.path.dirname(chunk_path), exist_ok=True)
+                with open(chunk_path, 'wb') as output:
+                    output.write(chunk)
+            chunks.append([chunk_hash, len(chunk)])
+    return chunks
+
+def assemble_file(chunk_paths, destination):
+    # Concatenates the cached chunks into destination and returns its SHA-256.
+    sha256_hash = hashlib.sha256()
+    with open(destination, 'wb') as output:
+        for chunk_path in chunk_paths:
+            with open(chunk_path, 'rb') as f:
+                data = f.read()
+            output.write(data)
+            sha256_hash.update(data)
+    fsync_path(destination)
+    return sha256_hash.hexdigest()
+
+class ChunkCache:
+    # Chunks already downloaded, kept across runs so data shared by files or by
+    # releases is transferred only once. Least recently used chunks are dropped
+    # once the cache is over max_size bytes (0 = unlimited).
+    def __init__(self, directory, max_size=0):
+        self.directory = directory
+        self.max_size = max_size
+        self.locks = {}
+
+    def path(self, chunk_hash):
+        if not is_chunk_hash(chunk_hash):
+            raise ValueError(f"Invalid chunk hash {chunk_hash!r}")
+        return os.path.join(self.directory, chunk_hash[:2], chunk_hash)
+
+    async def ensure(self, chunk_hash, fetch):
+        # fetch(destination) downloads and verifies the chunk; concurrent
+        # requests for the same chunk wait for a single download.
+        lock = self.locks.setdefault(chunk_hash, asyncio.Lock())
+        async with lock:
+            chunk_path = self.path(chunk_hash)
+            if os.path.exists(chunk_path):
+                os.utime(chunk_path)
+                return True
+            return await fetch(chunk_path)
+
+    def prune(self):
+        if not self.max_size or not os.path.isdir(self.directory):
+            return
+        chunks = []
+        for root, _, files in os.walk(self.directory):
+            for file_name in files:
+                chunk_path = os.path.join(root, file_name)
+                try:
+                    chunk_stat = os.stat(chunk_path)
+                except OSError:
+                    continue
+                chunks.append((chunk_stat.st_mtime, chunk_stat.st_size, chunk_path))
+        total_size = sum(size for _, size, _ in chunks)
+        removed = 0
+        for _, size, chunk_path in sorted(chunks):
+            if total_size <= self.max_size:
+                break
+            try:
+                os.remove(chunk_path)
+                total_size -= size
+                removed += 1
+            except OSError as e:
+                log_error(f"Error removing cached chunk {chunk_path}: {e}")
+        if removed:
+            log_info(f'Removed {removed} chunk(s) from the chunk cache')
@@ -0,0 +1,90 @@
+import gzip
+import zlib
+import shutil
+
+# Compressions a manifest may declare for a file's object on the server. zstd
+# needs the optional zstandard package.
+COMPRESSION_SUFFIXES = {'gzip': '.gz', 'zstd': '.zst'}
+# Most output produced by one gzip decompression step.
+DECOMPRESS_STEP = 1048576
+# zstandard has no output limit, so input is fed in small slices instead: a zstd
+# block expands to at most 128 KB and takes at least 4 bytes, which keeps the
+# output of one step to a few MB.
+ZSTD_INPUT_STEP = 256
+
+class UnsupportedCompression(Exception):
+    pass
+
+def load_zstandard():
+    try:
+        import zstandard
+    except ImportError:
+        raise UnsupportedCompression("zstd objects require the zstandard package")
+    return zstandard
+
+def is_supported(compression):
+    if compression == 'gzip':
+        return True
+    if compression == 'zstd':
+        try:
+            load_zstandard()
+            return True
+        except UnsupportedCompression:
+            return False
+    return False
+
+class GzipDecompressor:
+    def __init__(self):
+        self.decompressor = zlib.decompressobj(16 + zlib.MAX_WBITS)
+
+    def decompress(self, data):
+        # Yields the output in pieces of at most DECOMPRESS_STEP bytes, so a
+        # small, highly compressed input never expands all at once in memory.
+        # A full step may leave output pending even once all input is taken, so
+        # keep going until a step comes back short.
+        while not self.decompressor.eof:
+            piece = self.decompressor.decompress(data, DECOMPRESS_STEP)
+            data = self.decompressor.unconsumed_tail
+            if piece:
+                yield piece
+            if not data and len(piece) < DECOMPRESS_STEP:
+                break
+
+    def flush(self):
+        data = self.decompressor.flush()
+        if not self.decompressor.eof:
+            raise EOFError("gzip stream is truncated")
+        return data
+
+class ZstdDecompressor:
+    def __init__(self):
+        self.decompressor = load_zstandard().ZstdDecompressor().decompressobj()
+
+    def decompress(self, data):
+        for start in range(0, len(data), ZSTD_INPUT_STEP):
+            piece = self.decompressor.decompress(data[start:start + ZSTD_INPUT_STEP])
+            if piece:
+                yield piece
+
+    def flush(self):
+        if not getattr(self.decompressor, 'eof', True):
+            raise EOFError("zstd stream is truncated")
+        return b''
+
+def create_decompressor(compression):
+    if compression == 'gzip':
+        return GzipDecompressor()
+    if compression == 'zstd':
+        return ZstdDecompressor()
+    raise UnsupportedCompression(f"Unsupported compression: {compression}")
+
+def compress_file(source_path, destination, compression):
+    with open(source_path, 'rb') as source, open(destination, 'wb') as output:
+        if compression == 'gzip':
+            # mtime=0 keeps the object identical between runs.
+            with gzip.GzipFile(fileobj=output, mode='wb', mtime=0) as compressed:
+                shutil.copyfileobj(source, compressed)
+        elif compression == 'zstd':
+            load_zstandard().ZstdCompressor(level=19).copy_stream(source, output)
+        else:
+            raise UnsupportedCompression(f"Unsupported compression: {compression}")
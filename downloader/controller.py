@@ -0,0 +1,49 @@
+import asyncio
+import threading
+from settings import DOWNLOAD_BURST_SIZE
+from downloader.throttle import bandwidth_limiter
+
+PAUSE_POLL_INTERVAL = 0.2
+
+class DownloadCancelled(Exception):
+    pass
+
+class TransferController:
+    # Methods may be called from any thread (the GUI calls them from the Qt main
+    # thread while the update runs on its own event loop), so state is kept in
+    # threading primitives and polled from the download coroutines.
+    def __init__(self, num_segments=4):
+        self._running = threading.Event()
+        self._running.set()
+        self._cancelled = threading.Event()
+        self.num_segments = num_segments
+
+    @property
+    def paused(self):
+        return not self._running.is_set()
+
+    @property
+    def cancelled(self):
+        return self._cancelled.is_set()
+
+    def pause(self):
+        self._running.clear()
+
+    def resume(self):
+        self._running.set()
+
+    def cancel(self):
+        self._cancelled.set()
+        self._running.set()
+
+    def set_speed_limit(self, kilobytes_per_second):
+        bandwidth_limiter.set_rate(kilobytes_per_second * 1024, DOWNLOAD_BURST_SIZE * 1024)
+
+    def set_connections(self, num_segments):
+        self.num_segments = max(1, num_segments)
+
+    async def checkpoint(self):
+        while not self._running.is_set():
+            await asyncio.sleep(PAUSE_POLL_INTERVAL)
+        if self._cancelled.is_set():
+            raise DownloadCancelled("Download cancelled by user")
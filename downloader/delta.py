@@ -0,0 +1,107 @@
+import zlib
+import struct
+import hashlib
+from downloader.blocks import build_block_index, find_block, scan_for_blocks
+from downloader.file_manager import fsync_path
+
+# Delta files are a magic line followed by a zlib stream of instructions:
+#   C <offset:u64> <length:u64>   copy bytes from the source file
+#   A <length:u32> <data>          append literal bytes
+#   E                              end of delta
+DELTA_MAGIC = b'PATCHER-DELTA1\n'
+DELTA_BLOCK_SIZE = 2048
+COPY_CHUNK_SIZE = 1048576
+
+class InvalidDelta(Exception):
+    pass
+
+class DeltaWriter:
+    def __init__(self, file):
+        self.file = file
+        self.compressor = zlib.compressobj(9)
+        self.pending_copy = None
+        self.file.write(DELTA_MAGIC)
+
+    def write(self, data):
+        self.file.write(self.compressor.compress(data))
+
+    def copy(self, offset, length):
+        # Consecutive source blocks collapse into one instruction.
+        if self.pending_copy and self.pending_copy[0] + self.pending_copy[1] == offset:
+            self.pending_copy[1] += length
+            return
+        self.flush_copy()
+        self.pending_copy = [offset, length]
+
+    def flush_copy(self):
+        if self.pending_copy:
+            self.write(b'C' + struct.pack('>QQ', *self.pending_copy))
+            self.pending_copy = None
+
+    def add(self, data):
+        self.flush_copy()
+        self.write(b'A' + struct.pack('>I', len(data)) + data)
+
+    def close(self):
+        self.flush_copy()
+        self.write(b'E')
+        self.file.write(self.compressor.flush())
+
+class DeltaReader:
+    def __init__(self, file):
+        self.file = file
+        self.decompressor = zlib.decompressobj()
+        self.buffer = b''
+        if file.read(len(DELTA_MAGIC)) != DELTA_MAGIC:
+            raise InvalidDelta("Not a patcher delta file")
+
+    def read(self, size):
+        while len(self.buffer) < size:
+            chunk = self.file.read(COPY_CHUNK_SIZE)
+            if not chunk:
+                raise InvalidDelta("Delta file is truncated")
+            self.buffer += self.decompressor.decompress(chunk)
+        data, self.buffer = self.buffer[:size], self.buffer[size:]
+        return data
+
+def generate_delta(source_path, target_path, delta_path, block_size=DELTA_BLOCK_SIZE):
+    # Finds every block of the source file that appears anywhere in the target
+    # file (rsync style) and stores the rest as literal data.
+    index = build_block_index(source_path, block_size)
+    with open(target_path, 'rb') as target, open(delta_path, 'wb') as output:
+        writer = DeltaWriter(output)
+        for item in scan_for_blocks(target, block_size, lambda checksum, block: find_block(index, checksum, block)):
+            if item[0] == 'match':
+                writer.copy(item[1], len(item[2]))
+            else:
+                writer.add(item[1])
+        writer.close()
+
+def apply_delta(source_path, delta_path, output_path):
+    # Rebuilds the target file and returns its SHA-256 so it can be checked
+    # against the manifest without reading it back.
+    sha256_hash = hashlib.sha256()
+    with open(source_path, 'rb') as source, open(delta_path, 'rb') as delta, open(output_path, 'wb') as output:
+        reader = DeltaReader(delta)
+        while True:
+            opcode = reader.read(1)
+            if opcode == b'E':
+                break
+            if opcode == b'C':
+                offset, length = struct.unpack('>QQ', reader.read(16))
+                source.seek(offset)
+                while length > 0:
+                    data = source.read(min(length, COPY_CHUNK_SIZE))
+                    if not data:
+                        raise InvalidDelta("Delta copies past the end of the source file")
+                    output.write(data)
+                    sha256_hash.update(data)
+                    length -= len(data)
+            elif opcode == b'A':
+                data = reader.read(struct.unpack('>I', reader.read(4))[0])
+                output.write(data)
+                sha256_hash.update(data)
+            else:
+                raise InvalidDelta(f"Unknown delta instruction {opcode!r}")
+    fsync_path(output_path)
+    return sha256_hash.hexdigest()
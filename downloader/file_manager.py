@@ -1,9 +1,17 @@
 import os
+import fnmatch
 import hashlib
+import json
 import asyncio
+import time
 import aiofiles
+from functools import partial
 from logger import log_info, log_error
 
+TEMP_SUFFIX = '.patcher-tmp'
+# Patcher's own state (staging area, journals, backups) inside TARGET_FOLDER.
+STATE_DIR_NAME = '.patcher'
+
 async def create_directory_if_not_exists(directory_path):
     try:
         if not os.path.exists(directory_path):
@@ -27,32 +35,189 @@ async def get_file_hash(file_path):
         log_error(f"Error reading file {file_path}: {e}")
         raise Exception(f"Error reading file {file_path}: {e}")
 
-async def save_progress(file_path, progress):
+class OrderedHasher:
+    # SHA-256 of a file whose pieces are written out of order. Pieces that arrive
+    # ahead of the hash position are held in memory until the gap before them is
+    # filled. prewritten lists [start, end) ranges already on disk from an
+    # earlier run; those are read back when the hash position reaches them.
+    def __init__(self, file_path, prewritten=()):
+        self.file_path = file_path
+        self.sha256_hash = hashlib.sha256()
+        self.position = 0
+        self.pending = {}
+        self.prewritten = sorted((start, end) for start, end in prewritten if end > start)
+        self.advanced = asyncio.Event()
+
+    async def feed(self, offset, data):
+        if offset < self.position:
+            return
+        if offset == self.position:
+            self._update(data)
+            await self.drain()
+        else:
+            self.pending[offset] = data
+
+    async def drain(self):
+        while True:
+            if self.position in self.pending:
+                self._update(self.pending.pop(self.position))
+            elif self.prewritten and self.prewritten[0][0] == self.position:
+                start, end = self.prewritten.pop(0)
+                await self._update_from_disk(start, end)
+            else:
+                break
+
+    async def wait_until(self, position):
+        while self.position < position:
+            self.advanced.clear()
+            await self.advanced.wait()
+
+    def hexdigest(self):
+        return self.sha256_hash.hexdigest()
+
+    def _update(self, data):
+        self.sha256_hash.update(data)
+        self.position += len(data)
+        self.advanced.set()
+
+    async def _update_from_disk(self, start, end):
+        try:
+            async with aiofiles.open(self.file_path, 'rb') as f:
+                await f.seek(start)
+                while self.position < end:
+                    chunk = await f.read(min(65536, end - self.position))
+                    if not chunk:
+                        raise OSError(f"Unexpected end of file at byte {self.position}")
+                    self._update(chunk)
+        except OSError as e:
+            log_error(f"Error reading file {self.file_path}: {e}")
+            raise Exception(f"Error reading file {self.file_path}: {e}")
+
+async def preallocate_file(file_path, size):
+    try:
+        async with aiofiles.open(file_path, 'wb') as f:
+            await f.truncate(size)
+    except OSError as e:
+        log_error(f"Error preallocating file {file_path}: {e}")
+        raise Exception(f"Error preallocating file {file_path}: {e}")
+
+async def save_progress(file_path, journal):
+    temp_path = f"{file_path}.progress.tmp"
     try:
-        async with aiofiles.open(f"{file_path}.progress", 'w') as f:
-            await f.write(str(progress))
+        async with aiofiles.open(temp_path, 'w') as f:
+            await f.write(json.dumps(journal))
+        os.replace(temp_path, f"{file_path}.progress")
     except OSError as e:
         log_error(f"Error saving progress for {file_path}: {e}")
 
 async def load_progress(file_path):
     try:
         async with aiofiles.open(f"{file_path}.progress", 'r') as f:
-            return int(await f.read())
+            journal = json.loads(await f.read())
+        if not isinstance(journal, dict) or not isinstance(journal.get('segments'), list):
+            return None
+        return journal
     except (OSError, ValueError):
-        return 0
+        return None
 
 async def remove_progress_file(file_path):
+    if not os.path.exists(f"{file_path}.progress"):
+        return
     try:
         os.remove(f"{file_path}.progress")
     except OSError as e:
         log_error(f"Error removing progress file for {file_path}: {e}")
 
-async def clean_old_progress_files(directory, max_age):
+def temp_path_for(file_path):
+    return f"{file_path}{TEMP_SUFFIX}"
+
+def fsync_path(path, directory=False):
+    if directory and os.name == 'nt':
+        return
+    fd = os.open(path, os.O_RDONLY if directory else os.O_RDWR)
+    try:
+        os.fsync(fd)
+    finally:
+        os.close(fd)
+
+async def commit_file(temp_path, file_path):
+    # Flush the staged file to disk, then atomically swap it in: readers see
+    # either the complete old file or the complete new one, never a mix.
     loop = asyncio.get_running_loop()
-    current_time = loop.time()
-    for file_name in os.listdir(directory):
-        if file_name.endswith('.progress'):
-            file_path = os.path.join(directory, file_name)
-            file_age = current_time - os.path.getmtime(file_path)
-            if file_age > max_age:
-                os.remove(file_path)
\ No newline at end of file
+    try:
+        await loop.run_in_executor(None, fsync_path, temp_path)
+        os.replace(temp_path, file_path)
+        await loop.run_in_executor(None, partial(fsync_path, os.path.dirname(os.path.abspath(file_path)), directory=True))
+    except OSError as e:
+        log_error(f"Error replacing {file_path}: {e}")
+        raise Exception(f"Error replacing {file_path}: {e}")
+
+async def remove_temp_file(temp_path):
+    try:
+        if os.path.exists(temp_path):
+            os.remove(temp_path)
+    except OSError as e:
+        log_error(f"Error removing temporary file {temp_path}: {e}")
+
+async def clean_stale_download_files(directory, max_age):
+    # Reclaims what interrupted runs left behind: journals older than max_age and
+    # staged files that can no longer be resumed.
+    current_time = time.time()
+    for root, _, files in os.walk(directory):
+        for file_name in files:
+            file_path = os.path.join(root, file_name)
+            try:
+                if file_name.endswith(('.progress', '.progress.tmp')):
+                    if current_time - os.path.getmtime(file_path) > max_age:
+                        os.remove(file_path)
+                        log_info(f'Removed stale progress file: {file_path}')
+                elif file_name.endswith(TEMP_SUFFIX):
+                    journal_path = f"{file_path}.progress"
+                    if not os.path.exists(journal_path) or current_time - os.path.getmtime(journal_path) > max_age:
+                        os.remove(file_path)
+                        log_info(f'Removed leftover temporary file: {file_path}')
+            except OSError as e:
+                log_error(f"Error cleaning up {file_path}: {e}")
+
+def apply_file_metadata(path, mode=None, mtime=None):
+    if mode is not None:
+        os.chmod(path, mode)
+    if mtime is not None:
+        os.utime(path, (mtime, mtime))
+
+def is_protected(relative_path, patterns):
+    # A pattern protects the paths it matches and everything below them, so
+    # 'saves' and 'saves/*' both cover the whole saves directory.
+    parts = relative_path.split('/')
+    candidates = ['/'.join(parts[:i]) for i in range(1, len(parts) + 1)]
+    return any(fnmatch.fnmatch(candidate, pattern) for candidate in candidates for pattern in patterns)
+
+def find_unlisted_files(directory, listed_files, protected_patterns):
+    # Files in directory that neither the manifest nor a protected pattern
+    # accounts for. The patcher's own state and temporary files are skipped.
+    listed = {os.path.normcase(file_name) for file_name in listed_files}
+    unlisted = []
+    for root, dirs, files in os.walk(directory):
+        if os.path.samefile(root, directory):
+            dirs[:] = [d for d in dirs if d != STATE_DIR_NAME]
+        for file_name in files:
+            if file_name.endswith((TEMP_SUFFIX, '.progress', '.progress.tmp')):
+                continue
+            relative_path = os.path.relpath(os.path.join(root, file_name), directory).replace(os.sep, '/')
+            if os.path.normcase(relative_path) not in listed and not is_protected(relative_path, protected_patterns):
+                unlisted.append(relative_path)
+    return sorted(unlisted)
+
+def remove_empty_directories(directory, protected_patterns):
+    removed = []
+    for root, dirs, files in os.walk(directory, topdown=False):
+        relative_path = os.path.relpath(root, directory).replace(os.sep, '/')
+        if relative_path == '.' or relative_path.split('/')[0] == STATE_DIR_NAME or is_protected(relative_path, protected_patterns):
+            continue
+        try:
+            if not os.listdir(root):
+                os.rmdir(root)
+                removed.append(f'{relative_path}/')
+        except OSError as e:
+            log_error(f"Error removing directory {root}: {e}")
+    return removed
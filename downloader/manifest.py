@@ -0,0 +1,189 @@
+import json
+import posixpath
+from logger import log_error
+from downloader.file_manager import STATE_DIR_NAME, TEMP_SUFFIX
+from downloader.chunks import is_chunk_hash
+
+# Version of the JSON manifest layout. Legacy `name,hash[,priority]` file lists
+# are reported as format version 0.
+MANIFEST_FORMAT_VERSION = 1
+SUPPORTED_HASH_ALGORITHMS = ('sha256',)
+
+# Names the patcher uses for its own staging files and resume journals.
+RESERVED_SUFFIXES = (TEMP_SUFFIX, '.progress', '.progress.tmp')
+
+class InvalidManifest(Exception):
+    pass
+
+def is_safe_path(file_name):
+    # Manifest paths are relative, '/'-separated and must stay inside TARGET_FOLDER,
+    # outside the state directory and clear of the patcher's own file names.
+    if not file_name or file_name.startswith('/') or '\\' in file_name or ':' in file_name:
+        return False
+    # Windows and macOS file systems ignore case, so neither may these checks.
+    folded = file_name.casefold()
+    if folded.split('/')[0] == STATE_DIR_NAME.casefold() or folded.endswith(tuple(suffix.casefold() for suffix in RESERVED_SUFFIXES)):
+        return False
+    return not any(part in ('', '.', '..') for part in file_name.split('/')) and posixpath.normpath(file_name) == file_name
+
+def new_entry(name, file_hash, priority=0, size=None, mode=None, mtime=None, flags=(), patches=(), block_index=None, chunks=None, pack=None, compressed_object=None):
+    # patches are deltas from earlier releases of the file, keyed by the hash of
+    # the copy they apply to: {'source_hash', 'name', 'hash', 'size'}.
+    # block_index names the file's block checksum index: {'name', 'hash', 'size'}.
+    # chunks, when published, lists the file's content-defined chunks as
+    # [sha256, size] pairs in file order. pack locates a small file inside one
+    # of the manifest's packs: {'name', 'offset'}. object is a compressed copy
+    # of the file: {'name', 'compression', 'hash', 'size'} of the object as
+    # stored, while the entry's own hash and size describe the installed file.
+    return {
+        'name': name, 'hash': file_hash, 'priority': priority, 'size': size, 'mode': mode, 'mtime': mtime,
+        'flags': list(flags), 'patches': list(patches), 'block_index': block_index, 'chunks': chunks, 'pack': pack,
+        'object': compressed_object,
+    }
+
+def parse_compressed_object(item):
+    compressed_object = dict(parse_published_file(item), compression=item['compression'])
+    if not isinstance(compressed_object['compression'], str):
+        raise ValueError('bad compression')
+    return compressed_object
+
+def parse_pack_location(item, size, packs):
+    location = {'name': item['name'], 'offset': item['offset']}
+    pack = packs.get(location['name'])
+    if pack is None or not isinstance(location['offset'], int) or size is None or location['offset'] < 0:
+        raise ValueError('bad pack location')
+    if pack['size'] is not None and location['offset'] + size > pack['size']:
+        raise ValueError('pack location past the end of the pack')
+    return location
+
+def parse_chunks(items):
+    chunks = [[chunk_hash, int(size)] for chunk_hash, size in items]
+    if not all(is_chunk_hash(chunk_hash) and size >= 0 for chunk_hash, size in chunks):
+        raise ValueError('bad chunk list')
+    return chunks
+
+def parse_published_file(item):
+    published = {'name': item['name'], 'hash': item['hash'], 'size': item.get('size')}
+    if not is_safe_path(published['name']) or not isinstance(published['hash'], str) or not published['hash']:
+        raise ValueError(f"bad reference to {published['name']!r}")
+    return published
+
+def parse_patch(item):
+    patch = dict(parse_published_file(item), source_hash=item['source_hash'])
+    if not isinstance(patch['source_hash'], str) or not patch['source_hash']:
+        raise ValueError('bad patch source hash')
+    return patch
+
+def parse_mirror(url, weight=1):
+    try:
+        if not isinstance(url, str) or not url:
+            raise ValueError('no url')
+        return {'url': url, 'weight': float(weight)}
+    except (TypeError, ValueError) as e:
+        raise InvalidManifest(f"Invalid mirror {url!r}: {e}")
+
+def parse_legacy_manifest(lines):
+    manifest = {'format_version': 0, 'release': None, 'version': None, 'expires': None, 'packs': [], 'mirrors': [], 'files': [], 'invalid': []}
+    for line in lines:
+        if line.startswith('#'):
+            parts = line.split()
+            if parts[0] == '#mirror' and len(parts) >= 2:
+                manifest['mirrors'].append(parse_mirror(parts[1], parts[2] if len(parts) > 2 else 1))
+            continue
+        fields = line.split(',')
+        # Older generators wrote native paths, so Windows lists use backslashes.
+        fields[0] = fields[0].replace('\\', '/')
+        if len(fields) in (2, 3) and fields[0] and fields[1] and is_safe_path(fields[0]):
+            priority = int(fields[2]) if len(fields) == 3 and fields[2].strip().lstrip('-').isdigit() else 0
+            manifest['files'].append(new_entry(fields[0], fields[1], priority))
+        else:
+            log_error(f'Invalid file entry format: {line}')
+            manifest['invalid'].append(line)
+    return manifest
+
+def parse_json_manifest(text):
+    try:
+        document = json.loads(text)
+    except ValueError as e:
+        raise InvalidManifest(f"Manifest is not valid JSON: {e}")
+    if not isinstance(document, dict) or not isinstance(document.get('files'), list):
+        raise InvalidManifest("Manifest has no file list")
+    format_version = document.get('format_version')
+    if not isinstance(format_version, int) or format_version > MANIFEST_FORMAT_VERSION:
+        raise InvalidManifest(f"Unsupported manifest format version: {format_version}")
+    hash_algorithm = document.get('hash_algorithm', 'sha256')
+    if hash_algorithm not in SUPPORTED_HASH_ALGORITHMS:
+        raise InvalidManifest(f"Unsupported hash algorithm: {hash_algorithm}")
+
+    version = document.get('version')
+    if version is not None and not isinstance(version, int):
+        raise InvalidManifest(f"Invalid manifest version: {version!r}")
+    manifest = {
+        'format_version': format_version, 'release': document.get('release'), 'version': version, 'expires': document.get('expires'),
+        'packs': [], 'mirrors': [], 'files': [], 'invalid': [],
+    }
+    try:
+        manifest['packs'] = [parse_published_file(pack) for pack in document.get('packs', [])]
+    except (KeyError, TypeError, ValueError) as e:
+        raise InvalidManifest(f"Invalid pack list: {e}")
+    packs = {pack['name']: pack for pack in manifest['packs']}
+    if not isinstance(document.get('mirrors', []), list):
+        raise InvalidManifest("Invalid mirror list")
+    for mirror in document.get('mirrors', []):
+        if isinstance(mirror, str):
+            mirror = {'url': mirror}
+        if not isinstance(mirror, dict):
+            raise InvalidManifest(f"Invalid mirror {mirror!r}")
+        manifest['mirrors'].append(parse_mirror(mirror.get('url'), mirror.get('weight', 1)))
+    for item in document['files']:
+        try:
+            entry = new_entry(
+                item['name'], item['hash'], int(item.get('priority', 0)),
+                item.get('size'), item.get('mode'), item.get('mtime'), item.get('flags', []),
+                [parse_patch(patch) for patch in item.get('patches', [])],
+                parse_published_file(item['block_index']) if item.get('block_index') else None,
+                parse_chunks(item['chunks']) if item.get('chunks') is not None else None,
+                parse_pack_location(item['pack'], item.get('size'), packs) if item.get('pack') else None,
+                parse_compressed_object(item['object']) if item.get('object') else None,
+            )
+            if not is_safe_path(entry['name']) or not isinstance(entry['hash'], str) or not entry['hash']:
+                raise ValueError('bad name or hash')
+            if entry['size'] is not None and (not isinstance(entry['size'], int) or entry['size'] < 0):
+                raise ValueError('bad size')
+            if entry['object'] and entry['size'] is None:
+                # The size bounds how far the object may expand when decompressed.
+                raise ValueError('compressed object without a size')
+            # Permission bits only: a manifest may not make files setuid, setgid or sticky.
+            if entry['mode'] is not None and (not isinstance(entry['mode'], int) or not 0 <= entry['mode'] <= 0o777):
+                raise ValueError('bad mode')
+            if entry['mtime'] is not None and (isinstance(entry['mtime'], bool) or not isinstance(entry['mtime'], (int, float))):
+                raise ValueError('bad mtime')
+            if not isinstance(item.get('flags', []), list) or not all(isinstance(flag, str) for flag in entry['flags']):
+                raise ValueError('bad flags')
+        except (KeyError, TypeError, ValueError, AttributeError) as e:
+            log_error(f'Invalid file entry {item!r}: {e}')
+            manifest['invalid'].append(json.dumps(item))
+            continue
+        manifest['files'].append(entry)
+    return manifest
+
+def parse_manifest(text):
+    # JSON manifests start with an object; anything else is read as the legacy
+    # CSV format.
+    if text.lstrip().startswith('{'):
+        return parse_json_manifest(text)
+    return parse_legacy_manifest([line.strip() for line in text.split('\n') if line.strip()])
+
+def serialize_manifest(entries, release=None, mirrors=None, version=None, expires=None, packs=None):
+    # version only ever increases between published manifests; clients refuse
+    # to go back to a lower one.
+    document = {'format_version': MANIFEST_FORMAT_VERSION, 'release': release, 'version': version, 'expires': expires, 'hash_algorithm': 'sha256'}
+    if mirrors:
+        document['mirrors'] = mirrors
+    if packs:
+        document['packs'] = packs
+    document['files'] = [{key: value for key, value in entry.items() if value not in (None, [])} for entry in entries]
+    return json.dumps(document, indent=1, sort_keys=False)
+
+def serialize_legacy_manifest(entries):
+    return ''.join(f"{entry['name']},{entry['hash']}\n" for entry in entries)
@@ -0,0 +1,89 @@
+import os
+import json
+import hashlib
+from datetime import datetime, timezone
+from logger import log_info
+from downloader.manifest import InvalidManifest
+
+class MetadataError(InvalidManifest):
+    pass
+
+def format_expiry(timestamp):
+    return datetime.fromtimestamp(timestamp, timezone.utc).strftime('%Y-%m-%dT%H:%M:%SZ')
+
+def parse_expiry(expires):
+    # Expiry dates are ISO 8601 in UTC, e.g. 2026-01-31T00:00:00Z.
+    try:
+        return datetime.strptime(expires, '%Y-%m-%dT%H:%M:%SZ').replace(tzinfo=timezone.utc).timestamp()
+    except (TypeError, ValueError):
+        raise MetadataError(f"Invalid expiry date: {expires!r}")
+
+def check_expiry(expires, what, now):
+    if expires is not None and parse_expiry(expires) < now:
+        raise MetadataError(f"The {what} expired on {expires}; the server may be serving stale files")
+
+def parse_timestamp(data):
+    # The timestamp file is tiny and re-signed often with a short expiry. It
+    # names the current manifest by version and hash, so a mirror that keeps
+    # serving an old manifest is noticed as soon as the timestamp goes stale.
+    try:
+        document = json.loads(data)
+        return {
+            'version': int(document['version']),
+            'expires': document['expires'],
+            'manifest': {'version': document['manifest'].get('version'), 'hash': document['manifest']['hash'], 'size': int(document['manifest']['size'])},
+        }
+    except (KeyError, TypeError, ValueError, AttributeError) as e:
+        raise MetadataError(f"Malformed timestamp metadata: {e}")
+
+def build_timestamp(manifest_bytes, manifest_version, version, expires):
+    return json.dumps({
+        'version': version,
+        'expires': expires,
+        'manifest': {'version': manifest_version, 'hash': hashlib.sha256(manifest_bytes).hexdigest(), 'size': len(manifest_bytes)},
+    }, indent=1)
+
+def check_snapshot(timestamp, manifest_bytes, manifest):
+    expected = timestamp['manifest']
+    if len(manifest_bytes) != expected['size'] or hashlib.sha256(manifest_bytes).hexdigest() != expected['hash']:
+        raise MetadataError("The manifest does not match the timestamp metadata; the server may be serving stale files")
+    if expected['version'] is not None and manifest['version'] != expected['version']:
+        raise MetadataError(f"The manifest is version {manifest['version']} but the timestamp metadata names version {expected['version']}")
+
+class MetadataState:
+    # Highest manifest and timestamp versions accepted so far, kept in
+    # TARGET_FOLDER/.patcher so an older (even validly signed) manifest is
+    # refused unless a downgrade was explicitly requested.
+    def __init__(self, path):
+        self.path = path
+        try:
+            with open(path, 'r') as f:
+                self.versions = json.load(f)
+        except FileNotFoundError:
+            self.versions = {}
+        except (OSError, ValueError) as e:
+            raise MetadataError(f"Could not read the metadata state {path}: {e}")
+        if not isinstance(self.versions, dict):
+            raise MetadataError(f"Metadata state {path} is malformed")
+
+    def check_version(self, key, version, allow_downgrade=False):
+        seen = self.versions.get(key)
+        if seen is None:
+            return
+        if version is None or version < seen:
+            if not allow_downgrade:
+                raise MetadataError(f"Refusing {key.replace('_', ' ')} {version}: version {seen} was already installed")
+            log_info(f"Downgrading {key.replace('_', ' ')} from {seen} to {version} as requested")
+
+    def record(self, key, version, allow_downgrade=False):
+        if version is None:
+            return
+        if allow_downgrade or version > self.versions.get(key, version - 1):
+            self.versions[key] = version
+
+    def save(self):
+        os.makedirs(os.path.dirname(self.path), exist_ok=True)
+        temp_path = f"{self.path}.tmp"
+        with open(temp_path, 'w') as f:
+            json.dump(self.versions, f)
+        os.replace(temp_path, self.path)
@@ -0,0 +1,73 @@
+import time
+from settings import MIRRORS, MIRROR_MAX_FAILURES, MIRROR_BLACKLIST_TIME
+from logger import log_info, log_debug
+
+SMOOTHING_FACTOR = 0.3
+REFERENCE_TRANSFER_SIZE = 1048576  # bytes
+
+def smooth(previous, sample):
+    if previous is None:
+        return sample
+    return previous * (1 - SMOOTHING_FACTOR) + sample * SMOOTHING_FACTOR
+
+class Mirror:
+    def __init__(self, url, weight=1):
+        self.url = url if url.endswith('/') else f'{url}/'
+        self.weight = max(weight, 0.01)
+        self.latency = None
+        self.throughput = None
+        self.consecutive_failures = 0
+        self.blacklisted_until = 0
+
+    @property
+    def blacklisted(self):
+        return time.monotonic() < self.blacklisted_until
+
+    def expected_time(self, best_throughput):
+        # Estimated seconds to fetch a reference-sized file. Mirrors that have not
+        # been measured yet are assumed to be as fast as the best one seen so far,
+        # so every mirror gets a chance to prove itself.
+        latency = self.latency if self.latency is not None else 0
+        throughput = self.throughput or best_throughput
+        transfer_time = REFERENCE_TRANSFER_SIZE / throughput if throughput else 0
+        return latency + transfer_time
+
+class MirrorPool:
+    def __init__(self, mirrors=None):
+        self.mirrors = []
+        for mirror in mirrors if mirrors is not None else MIRRORS:
+            self.add(mirror['url'], mirror.get('weight', 1))
+
+    def add(self, url, weight=1):
+        mirror = Mirror(url, weight)
+        if any(existing.url == mirror.url for existing in self.mirrors):
+            return
+        self.mirrors.append(mirror)
+        log_debug(f'Mirror added: {mirror.url} (weight {mirror.weight})')
+
+    def ranked(self):
+        healthy = [mirror for mirror in self.mirrors if not mirror.blacklisted]
+        if not healthy:
+            # Everything is blacklisted: try the ones whose ban expires first
+            # rather than failing outright.
+            return sorted(self.mirrors, key=lambda mirror: mirror.blacklisted_until)
+        best_throughput = max((mirror.throughput or 0 for mirror in healthy), default=0)
+        return sorted(healthy, key=lambda mirror: mirror.expected_time(best_throughput) / mirror.weight)
+
+    def record_latency(self, mirror, seconds):
+        mirror.latency = smooth(mirror.latency, seconds)
+
+    def record_success(self, mirror, size, seconds):
+        mirror.consecutive_failures = 0
+        if size > 0 and seconds > 0:
+            mirror.throughput = smooth(mirror.throughput, size / seconds)
+
+    def record_failure(self, mirror):
+        mirror.consecutive_failures += 1
+        if mirror.consecutive_failures >= MIRROR_MAX_FAILURES:
+            mirror.blacklisted_until = time.monotonic() + MIRROR_BLACKLIST_TIME
+            mirror.consecutive_failures = 0
+            log_info(f'Mirror blacklisted for {MIRROR_BLACKLIST_TIME} seconds: {mirror.url}')
+
+    def __len__(self):
+        return len(self.mirrors)
@@ -1,139 +1,302 @@
-import aiohttp
 import asyncio
 import os
-from settings import DOWNLOAD_SPEED_LIMIT
+import hashlib
+from functools import partial
+from settings import SEGMENT_SIZE, MULTITHREADING_THRESHOLD
 from logger import log_info, log_error, log_debug
-from concurrent.futures import ThreadPoolExecutor
-from downloader.file_manager import get_file_hash, save_progress, load_progress, remove_progress_file
+from downloader.throttle import bandwidth_limiter
+from downloader.controller import DownloadCancelled, PAUSE_POLL_INTERVAL
+from downloader.transport import TransportRegistry, RemoteFileChanged, IncompleteTransfer
+from downloader.retry import RetryPolicy, PermanentError
+from downloader.scheduler import run_concurrently
+from downloader.compression import create_decompressor
+from downloader.file_manager import OrderedHasher, preallocate_file, save_progress, load_progress, remove_progress_file, temp_path_for, commit_file, remove_temp_file
 import aiofiles
 
-async def download_file_segment(session, url, start_byte, end_byte, part_num, destination):
-    headers = {'Range': f'bytes={start_byte}-{end_byte}'}
-    part_file_name = f"{destination}.part{part_num}"
-    async with session.get(url, headers=headers) as response:
-        response.raise_for_status()
-        async with aiofiles.open(part_file_name, 'ab') as file:
-            async for chunk in response.content.iter_chunked(1024):
-                await file.write(chunk)
-    return part_file_name
-
-async def combine_file_parts(destination, num_parts):
-    async with aiofiles.open(destination, 'wb') as final_file:
-        for part_num in range(num_parts):
-            part_file_name = f"{destination}.part{part_num}"
-            async with aiofiles.open(part_file_name, 'rb', buffering=0) as part_file:
-                while chunk := await part_file.read(8192):
-                    await final_file.write(chunk)
-            os.remove(part_file_name)
-
-async def download_file(file_url, destination, expected_checksum, callback=None, retry_count=3, timeout=10, num_threads=4):
-    max_backoff_time = 120
-    backoff_factor = 2
-    backoff_time = 1
-
-    throttle_interval = (8192 / (DOWNLOAD_SPEED_LIMIT * 1024)) if DOWNLOAD_SPEED_LIMIT > 0 else 0
-
-    for attempt in range(retry_count):
-        try:
-            async with aiohttp.ClientSession(timeout=aiohttp.ClientTimeout(total=timeout)) as session:
-                async with session.head(file_url) as response:
-                    response.raise_for_status()
-                    total_size_in_bytes = int(response.headers.get('Content-Length', 0))
-                    if total_size_in_bytes == 0:
-                        raise ValueError("Failed to retrieve the total size of the file.")
-
-                    part_size = total_size_in_bytes // num_threads
-
-                futures = []
-
-                log_debug(f'Starting download: {file_url}')
-
-                loop = asyncio.get_event_loop()
-                with ThreadPoolExecutor(max_workers=num_threads) as executor:
-                    for part_num in range(num_threads):
-                        start_byte = part_num * part_size
-                        end_byte = start_byte + part_size - 1 if part_num < num_threads - 1 else total_size_in_bytes - 1
-                        futures.append(loop.run_in_executor(executor, download_file_segment, session, file_url, start_byte, end_byte, part_num, destination))
-
-                    downloaded_size = 0
-                    for future in await asyncio.gather(*futures):
-                        await future
-                        downloaded_size += part_size
-                        await save_progress(destination, downloaded_size)
-                        if callback:
-                            callback(downloaded_size, total_size_in_bytes)
-                        if throttle_interval > 0:
-                            await asyncio.sleep(throttle_interval)
-
-                await combine_file_parts(destination, num_threads)
-                actual_checksum = await get_file_hash(destination)
-                if actual_checksum != expected_checksum:
-                    log_error(f'Checksum mismatch: expected {expected_checksum}, got {actual_checksum}')
-                    raise ValueError(f"Checksum mismatch: expected {expected_checksum}, got {actual_checksum}")
-
-                log_info(f'File downloaded and checksum verified: {destination}')
-                await remove_progress_file(destination)
+JOURNAL_SAVE_INTERVAL = 1
+
+class ChecksumMismatch(PermanentError):
+    pass
+
+def split_into_segments(total_size, segment_size=SEGMENT_SIZE):
+    # Segments are handed out to the connections in file order, so they finish
+    # roughly in order and the file can be hashed as it arrives.
+    segment_size = max(1, segment_size)
+    return [
+        {'start': start_byte, 'end': min(start_byte + segment_size, total_size) - 1, 'downloaded': 0}
+        for start_byte in range(0, total_size, segment_size)
+    ]
+
+def new_journal(file_url, expected_checksum, total_size, validator):
+    return {
+        'url': file_url,
+        'expected_hash': expected_checksum,
+        'total_size': total_size,
+        'validator': validator,
+        'segments': split_into_segments(total_size),
+    }
+
+def seeded_journal(file_url, expected_checksum, total_size, validator, present_ranges):
+    # Journal for a download whose present_ranges were filled from local data:
+    # they count as already downloaded, so only the gaps are requested.
+    segments = []
+    position = 0
+    for start, end in present_ranges + [(total_size, total_size)]:
+        for segment in split_into_segments(start - position):
+            segments.append({'start': segment['start'] + position, 'end': segment['end'] + position, 'downloaded': 0})
+        if end > start:
+            segments.append({'start': start, 'end': end - 1, 'downloaded': end - start})
+        position = end
+    journal = new_journal(file_url, expected_checksum, total_size, validator)
+    journal['segments'] = segments
+    journal['seeded'] = True
+    return journal
+
+async def seed_download(file_url, destination, expected_checksum, file_info, present_ranges):
+    # destination must already hold the seeded bytes at its final size.
+    await save_progress(temp_path_for(destination), seeded_journal(file_url, expected_checksum, file_info['size'], file_info['validator'], present_ranges))
+
+def can_resume(journal, destination, file_url, expected_checksum, total_size, validator):
+    if journal is None or not os.path.exists(destination):
+        return False
+    if os.path.getsize(destination) != total_size:
+        return False
+    if (journal.get('url'), journal.get('expected_hash'), journal.get('total_size')) != (file_url, expected_checksum, total_size):
+        return False
+    # Seeded ranges did not come from the server, so they need no validator;
+    # the final hash check catches a file that changed in between.
+    if not (validator['etag'] or validator['last_modified']) and not journal.get('seeded'):
+        return False
+    return journal.get('validator') == validator
+
+async def download_file_segment(transport, url, segment, part_num, destination, validator, hasher, on_progress=None, controller=None):
+    start_byte = segment['start'] + segment['downloaded']
+    end_byte = segment['end']
+    if start_byte > end_byte:
+        return
+    async with aiofiles.open(destination, 'r+b') as file:
+        await file.seek(start_byte)
+        async for chunk in transport.get_range(url, start_byte, end_byte, validator if segment['downloaded'] > 0 else None):
+            if controller:
+                await controller.checkpoint()
+            await bandwidth_limiter.consume(len(chunk))
+            if segment['start'] + segment['downloaded'] + len(chunk) > end_byte + 1:
+                raise ValueError(f"Segment {part_num} of {url} returned more data than requested")
+            await file.write(chunk)
+            await hasher.feed(segment['start'] + segment['downloaded'], chunk)
+            segment['downloaded'] += len(chunk)
+            if on_progress:
+                on_progress()
+    if segment['start'] + segment['downloaded'] != end_byte + 1:
+        raise IncompleteTransfer(f"Segment {part_num} of {url} is incomplete: ended at byte {segment['start'] + segment['downloaded']} of {end_byte + 1}")
+
+async def save_journal_periodically(destination, journal):
+    while True:
+        await asyncio.sleep(JOURNAL_SAVE_INTERVAL)
+        await save_progress(destination, journal)
+
+async def download_segmented(transport, file_url, destination, expected_checksum, file_info, callback=None, num_segments=4, controller=None, retry_policy=None):
+    retry_policy = retry_policy or RetryPolicy()
+    total_size_in_bytes = file_info['size']
+    validator = file_info['validator']
+    journal = await load_progress(destination)
+    if can_resume(journal, destination, file_url, expected_checksum, total_size_in_bytes, validator):
+        log_info(f'Resuming download for {destination} from {sum(s["downloaded"] for s in journal["segments"])} bytes')
+    else:
+        if journal is not None:
+            log_info(f'Discarding stale resume journal for {destination}')
+        journal = new_journal(file_url, expected_checksum, total_size_in_bytes, validator)
+        await preallocate_file(destination, total_size_in_bytes)
+    await save_progress(destination, journal)
+    segments = journal['segments']
+    num_connections = max(1, min(num_segments, len(segments)))
+
+    # Bytes written by an earlier, interrupted run are the only ones read back
+    # from disk; everything downloaded now is hashed straight from memory.
+    hasher = OrderedHasher(destination, [(segment['start'], segment['start'] + segment['downloaded']) for segment in segments])
+    reorder_window = 2 * num_connections * SEGMENT_SIZE
+    pending_segments = iter(enumerate(segments))
+    exhausted = False
+
+    def report_progress():
+        if callback:
+            callback(sum(segment['downloaded'] for segment in segments), total_size_in_bytes)
+
+    async def connection(index):
+        nonlocal exhausted
+        while True:
+            # Lowering the connection count takes effect at the next segment:
+            # connections above it stay idle until it is raised again, up to the
+            # number this download started with.
+            while controller and index >= controller.num_segments and not exhausted:
+                await controller.checkpoint()
+                await asyncio.sleep(PAUSE_POLL_INTERVAL)
+            part_num, segment = next(pending_segments, (None, None))
+            if segment is None:
+                exhausted = True
                 return
-        except aiohttp.ClientError as e:
-            log_error(f'HTTP error: {e}')
-        except asyncio.TimeoutError as e:
-            log_error(f'Timeout error: {e}')
-        except Exception as e:
-            log_error(f'Error downloading file {file_url}: {e}')
-
-        if attempt < retry_count - 1:
-            backoff_time = min(backoff_time * backoff_factor, max_backoff_time)
-            log_info(f'Retrying to download file: {file_url}, Attempt: {attempt + 2}, Waiting for {backoff_time} seconds')
-            await asyncio.sleep(backoff_time)
-        else:
-            log_error(f'Failed to download file after {retry_count} attempts: {file_url}')
+            if segment['start'] + segment['downloaded'] > segment['end']:
+                continue
+            # Do not run too far ahead of the hash position, or out-of-order data
+            # piles up in memory while waiting to be hashed.
+            await hasher.wait_until(segment['start'] - reorder_window)
+            # A changed remote file cannot be fixed by retrying the segment with
+            # the same validator; it goes straight up so the whole download
+            # restarts with a fresh journal.
+            await retry_policy.call(
+                partial(download_file_segment, transport, file_url, segment, part_num, destination, validator, hasher, report_progress, controller),
+                f'segment {part_num} of {file_url}',
+                fatal=(RemoteFileChanged,),
+            )
+
+    log_debug(f'Starting download: {file_url} in {len(segments)} segment(s) over {num_connections} connection(s)')
+    journal_task = asyncio.ensure_future(save_journal_periodically(destination, journal))
+    try:
+        await hasher.drain()
+        await run_concurrently(connection(index) for index in range(num_connections))
+        await hasher.drain()
+    finally:
+        journal_task.cancel()
+        await asyncio.gather(journal_task, return_exceptions=True)
+        await save_progress(destination, journal)
+
+    if hasher.position != total_size_in_bytes:
+        raise IncompleteTransfer(f"Only {hasher.position} of {total_size_in_bytes} bytes of {file_url} could be hashed")
+    return hasher.hexdigest()
+
+async def download_stream(transport, file_url, destination, file_info, callback=None, controller=None):
+    total_size_in_bytes = file_info['size']
+    downloaded = 0
+    log_debug(f'Starting single-stream download: {file_url}')
+    await remove_progress_file(destination)
+    sha256_hash = hashlib.sha256()
+    async with aiofiles.open(destination, 'wb') as file:
+        async for chunk in transport.get(file_url):
+            if controller:
+                await controller.checkpoint()
+            await bandwidth_limiter.consume(len(chunk))
+            await file.write(chunk)
+            sha256_hash.update(chunk)
+            downloaded += len(chunk)
             if callback:
-                callback(None, None, error='Failed to download file after maximum retry attempts')
-            raise Exception(f"Failed to download file after {retry_count} attempts: {file_url}")
-
-async def resume_download(file_url, destination, expected_checksum, callback=None, retry_count=3, timeout=10, num_threads=4):
-    resume_position = await load_progress(destination)
-    throttle_interval = (8192 / (DOWNLOAD_SPEED_LIMIT * 1024)) if DOWNLOAD_SPEED_LIMIT > 0 else 0
-
-    if resume_position > 0:
-        log_info(f'Resuming download for {destination} from byte {resume_position}')
-        async with aiohttp.ClientSession(timeout=aiohttp.ClientTimeout(total=timeout)) as session:
-            async with session.head(file_url) as response:
-                response.raise_for_status()
-                total_size_in_bytes = int(response.headers.get('Content-Length', 0))
-                if total_size_in_bytes == 0:
-                    raise ValueError("Failed to retrieve the total size of the file.")
-
-                remaining_size = total_size_in_bytes - resume_position
-                part_size = remaining_size // num_threads
-
-                futures = []
-                log_debug(f'Resuming download: {file_url}')
-                loop = asyncio.get_event_loop()
-                with ThreadPoolExecutor(max_workers=num_threads) as executor:
-                    for part_num in range(num_threads):
-                        start_byte = resume_position + part_num * part_size
-                        end_byte = start_byte + part_size - 1 if part_num < num_threads - 1 else total_size_in_bytes - 1
-                        futures.append(loop.run_in_executor(executor, download_file_segment, session, file_url, start_byte, end_byte, part_num, destination))
-
-                    downloaded_size = resume_position
-                    for future in await asyncio.gather(*futures):
-                        await future
-                        downloaded_size += part_size
-                        await save_progress(destination, downloaded_size)
-                        if callback:
-                            callback(downloaded_size, total_size_in_bytes)
-                        if throttle_interval > 0:
-                            await asyncio.sleep(throttle_interval)
-
-                await combine_file_parts(destination, num_threads)
-                actual_checksum = await get_file_hash(destination)
-                if actual_checksum != expected_checksum:
-                    log_error(f'Checksum mismatch: expected {expected_checksum}, got {actual_checksum}')
-                    raise ValueError(f"Checksum mismatch: expected {expected_checksum}, got {actual_checksum}")
-
-                log_info(f'File downloaded and checksum verified: {destination}')
-                await remove_progress_file(destination)
-                return
-    else:
-        await download_file(file_url, destination, expected_checksum, callback, retry_count, timeout, num_threads)
\ No newline at end of file
+                callback(downloaded, total_size_in_bytes or 0)
+    if total_size_in_bytes is not None and downloaded != total_size_in_bytes:
+        raise IncompleteTransfer(f"Incomplete download of {file_url}: got {downloaded} of {total_size_in_bytes} bytes")
+    return sha256_hash.hexdigest()
+
+async def download_file(file_url, destination, expected_checksum, callback=None, retry_policy=None, num_segments=4, controller=None, file_info=None, transports=None):
+    if transports is None:
+        async with TransportRegistry() as transports:
+            return await download_file(file_url, destination, expected_checksum, callback, retry_policy, num_segments, controller, file_info, transports)
+
+    retry_policy = retry_policy or RetryPolicy()
+    transport = transports.for_url(file_url)
+    probed = {'file_info': file_info}
+    # Everything is written to a temporary file next to the destination and only
+    # renamed over it once verified, so a crash or a bad download never damages
+    # the installed file.
+    staging_path = temp_path_for(destination)
+
+    async def attempt():
+        if controller:
+            await controller.checkpoint()
+        # A supplied probe result is only trusted for the first attempt.
+        current_info = probed['file_info'] or await transport.stat(file_url)
+        probed['file_info'] = None
+
+        # Small files are fetched in one request without preallocation or a
+        # journal, unless a journal is already there to resume or was seeded.
+        segmented = current_info['ranges'] and current_info['size'] and (current_info['size'] > MULTITHREADING_THRESHOLD or await load_progress(staging_path) is not None)
+        try:
+            if segmented:
+                actual_checksum = await download_segmented(transport, file_url, staging_path, expected_checksum, current_info, callback, num_segments, controller, retry_policy)
+            else:
+                actual_checksum = await download_stream(transport, file_url, staging_path, current_info, callback, controller)
+        except RemoteFileChanged as e:
+            log_error(f'{e}, restarting download')
+            await remove_progress_file(staging_path)
+            raise
+
+        if actual_checksum != expected_checksum:
+            log_error(f'Checksum mismatch: expected {expected_checksum}, got {actual_checksum}')
+            await remove_progress_file(staging_path)
+            await remove_temp_file(staging_path)
+            raise ChecksumMismatch(f"Checksum mismatch: expected {expected_checksum}, got {actual_checksum}")
+
+    try:
+        log_debug(f'Starting download: {file_url}')
+        await retry_policy.call(attempt, f'download of {file_url}')
+    except DownloadCancelled:
+        log_info(f'Download cancelled, resume journal kept for {destination}')
+        raise
+    except Exception as e:
+        log_error(f'Failed to download file {file_url}: {e}')
+        if callback:
+            callback(None, None, error=f'Failed to download file: {file_url}')
+        raise Exception(f"Failed to download file {file_url}: {e}")
+
+    await remove_progress_file(staging_path)
+    await commit_file(staging_path, destination)
+    log_info(f'File downloaded and checksum verified: {destination}')
+
+async def download_compressed(object_url, destination, expected_checksum, expected_size, compressed_object, callback=None, retry_policy=None, controller=None, transports=None):
+    # Streams a compressed object and writes it out decompressed. Both the
+    # object as sent and the file it expands to are checked against the
+    # manifest, and the output may never grow past expected_size.
+    if transports is None:
+        async with TransportRegistry() as transports:
+            return await download_compressed(object_url, destination, expected_checksum, expected_size, compressed_object, callback, retry_policy, controller, transports)
+
+    retry_policy = retry_policy or RetryPolicy()
+    transport = transports.for_url(object_url)
+    staging_path = temp_path_for(destination)
+
+    async def attempt():
+        if controller:
+            await controller.checkpoint()
+        decompressor = create_decompressor(compressed_object['compression'])
+        compressed_hash, sha256_hash = hashlib.sha256(), hashlib.sha256()
+        received = written = 0
+        async with aiofiles.open(staging_path, 'wb') as file:
+            async def write(data):
+                nonlocal written
+                written += len(data)
+                if written > expected_size:
+                    raise ChecksumMismatch(f"{object_url} expands past the expected {expected_size} bytes")
+                await file.write(data)
+                sha256_hash.update(data)
+
+            async for chunk in transport.get(object_url):
+                if controller:
+                    await controller.checkpoint()
+                await bandwidth_limiter.consume(len(chunk))
+                compressed_hash.update(chunk)
+                received += len(chunk)
+                for piece in decompressor.decompress(chunk):
+                    await write(piece)
+                if callback:
+                    callback(received, compressed_object['size'] or 0)
+            try:
+                await write(decompressor.flush())
+            except EOFError as e:
+                raise IncompleteTransfer(f"{object_url}: {e}")
+
+        if compressed_hash.hexdigest() != compressed_object['hash']:
+            raise ChecksumMismatch(f"Checksum mismatch for {object_url}: expected {compressed_object['hash']}, got {compressed_hash.hexdigest()}")
+        if sha256_hash.hexdigest() != expected_checksum:
+            raise ChecksumMismatch(f"Checksum mismatch after decompressing {object_url}: expected {expected_checksum}, got {sha256_hash.hexdigest()}")
+
+    try:
+        log_debug(f'Starting compressed download: {object_url}')
+        await remove_progress_file(staging_path)
+        await retry_policy.call(attempt, f'download of {object_url}')
+    except DownloadCancelled:
+        await remove_temp_file(staging_path)
+        raise
+    except Exception as e:
+        await remove_temp_file(staging_path)
+        log_error(f'Failed to download file {object_url}: {e}')
+        raise Exception(f"Failed to download file {object_url}: {e}")
+
+    await commit_file(staging_path, destination)
+    log_info(f'File downloaded, decompressed and checksum verified: {destination}')
@@ -0,0 +1,105 @@
+import os
+import asyncio
+import hashlib
+import aiofiles
+from downloader.file_manager import get_file_hash, create_directory_if_not_exists
+
+# Small files are published concatenated into pack files so a client fetches
+# many of them with one request. A file's manifest entry gives the pack and its
+# offset there; its size and hash are the file's own.
+PACK_DIR = 'packs'
+PACK_RANGE_GAP = 65536  # unneeded bytes fetched rather than starting a new range
+
+async def build_packs(filelist, target_folder, threshold, pack_size):
+    # Returns the published packs as {'name', 'hash', 'size'} and records each
+    # packed file's location in entry['pack'].
+    groups = []
+    for entry in filelist:
+        if not 0 < entry['size'] < threshold:
+            continue
+        if not groups or groups[-1]['size'] + entry['size'] > pack_size:
+            groups.append({'name': f'{PACK_DIR}/pack-{len(groups):05d}.pack', 'size': 0, 'members': []})
+        entry['pack'] = {'name': groups[-1]['name'], 'offset': groups[-1]['size']}
+        groups[-1]['size'] += entry['size']
+        groups[-1]['members'].append(entry)
+
+    packs = []
+    for group in groups:
+        pack_path = os.path.join(target_folder, group['name'])
+        await create_directory_if_not_exists(os.path.dirname(pack_path))
+        with open(pack_path, 'wb') as output:
+            for entry in group['members']:
+                with open(os.path.join(target_folder, entry['name']), 'rb') as f:
+                    output.write(f.read())
+        packs.append({'name': group['name'], 'hash': await get_file_hash(pack_path), 'size': group['size']})
+    return packs
+
+def plan_pack_ranges(members, pack_size, gap=PACK_RANGE_GAP):
+    # Groups the needed members (entries with a 'pack' location) into byte
+    # ranges of the pack, merging neighbours closer than gap. When most of the
+    # pack is needed anyway, the whole pack is fetched in one range.
+    members = sorted(members, key=lambda entry: entry['pack']['offset'])
+    if pack_size and sum(entry['size'] for entry in members) * 2 >= pack_size:
+        return [(0, pack_size - 1, members)]
+    ranges = []
+    for entry in members:
+        start, end = entry['pack']['offset'], entry['pack']['offset'] + entry['size'] - 1
+        if ranges and start - ranges[-1][1] <= gap:
+            ranges[-1] = (ranges[-1][0], max(ranges[-1][1], end), ranges[-1][2] + [entry])
+        else:
+            ranges.append((start, end, [entry]))
+    return ranges
+
+async def extract_members(chunks, start, members, destination_for, on_data=None):
+    # Splits the bytes of a pack range (an async iterator starting at pack
+    # offset start) into the member files and returns {name: sha256}. Bytes
+    # between members are skipped; the stream is left as soon as the last
+    # member is complete.
+    hashes = {}
+    position = start
+    pending = iter(members)
+    current = None
+
+    async def next_member():
+        nonlocal current
+        current = next(pending, None)
+        if current is None:
+            return
+        destination = destination_for(current)
+        await create_directory_if_not_exists(os.path.dirname(destination))
+        current = {'entry': current, 'file': await aiofiles.open(destination, 'wb'), 'hash': hashlib.sha256(), 'remaining': current['size']}
+
+    async def finish_member():
+        # Members are committed into the install later; they must be on disk
+        # before that, like any other downloaded file.
+        await current['file'].flush()
+        await asyncio.get_running_loop().run_in_executor(None, os.fsync, current['file'].fileno())
+        await current['file'].close()
+        hashes[current['entry']['name']] = current['hash'].hexdigest()
+        await next_member()
+
+    await next_member()
+    try:
+        async for chunk in chunks:
+            if on_data:
+                await on_data(len(chunk))
+            view = memoryview(chunk)
+            while current is not None and view:
+                offset = current['entry']['pack']['offset']
+                if position < offset:
+                    skip = min(offset - position, len(view))
+                    view, position = view[skip:], position + skip
+                    continue
+                data = bytes(view[:current['remaining']])
+                await current['file'].write(data)
+                current['hash'].update(data)
+                current['remaining'] -= len(data)
+                view, position = view[len(data):], position + len(data)
+                if current['remaining'] == 0:
+                    await finish_member()
+            if current is None:
+                break
+    finally:
+        if current is not None:
+            await current['file'].close()
+    return hashes
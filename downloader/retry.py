@@ -0,0 +1,71 @@
+import random
+import asyncio
+import datetime
+import aiohttp
+from email.utils import parsedate_to_datetime
+from settings import RETRY_MAX_ATTEMPTS, RETRY_BASE_DELAY, RETRY_MAX_DELAY, RETRY_AFTER_MAX
+from logger import log_info
+from downloader.controller import DownloadCancelled
+from downloader.transport import RemoteFileChanged, IncompleteTransfer
+
+TRANSIENT_STATUSES = {408, 425, 429, 500, 502, 503, 504}
+
+class PermanentError(Exception):
+    pass
+
+class RetriesExhausted(PermanentError):
+    pass
+
+def parse_retry_after(value):
+    if not value:
+        return None
+    value = value.strip()
+    if value.isdigit():
+        return int(value)
+    try:
+        retry_at = parsedate_to_datetime(value)
+    except (TypeError, ValueError):
+        return None
+    return max(0, (retry_at - datetime.datetime.now(datetime.timezone.utc)).total_seconds())
+
+class RetryPolicy:
+    def __init__(self, max_attempts=RETRY_MAX_ATTEMPTS, base_delay=RETRY_BASE_DELAY, max_delay=RETRY_MAX_DELAY, retry_after_max=RETRY_AFTER_MAX):
+        self.max_attempts = max(1, max_attempts)
+        self.base_delay = base_delay
+        self.max_delay = max_delay
+        self.retry_after_max = retry_after_max
+
+    def is_transient(self, error):
+        if isinstance(error, (PermanentError, DownloadCancelled)):
+            return False
+        if isinstance(error, aiohttp.ClientResponseError):
+            return error.status in TRANSIENT_STATUSES or error.status >= 500
+        if isinstance(error, (RemoteFileChanged, IncompleteTransfer, asyncio.TimeoutError, TimeoutError, ConnectionError)):
+            return True
+        return isinstance(error, (aiohttp.ClientConnectionError, aiohttp.ClientPayloadError))
+
+    def delay(self, attempt, error):
+        if isinstance(error, RemoteFileChanged):
+            return 0
+        if isinstance(error, aiohttp.ClientResponseError) and error.status in (429, 503):
+            retry_after = parse_retry_after((error.headers or {}).get('Retry-After'))
+            if retry_after is not None:
+                return min(retry_after, self.retry_after_max)
+        # Full jitter: spreads out clients that failed at the same moment instead
+        # of having them all come back together.
+        return random.uniform(0, min(self.max_delay, self.base_delay * 2 ** attempt))
+
+    async def call(self, operation, description, fatal=()):
+        attempt = 0
+        while True:
+            try:
+                return await operation()
+            except Exception as e:
+                if isinstance(e, fatal) or not self.is_transient(e):
+                    raise
+                attempt += 1
+                if attempt >= self.max_attempts:
+                    raise RetriesExhausted(f"{description} failed after {attempt} attempts: {e}") from e
+                delay = self.delay(attempt, e)
+                log_info(f'Retrying {description} in {delay:.1f} seconds (attempt {attempt + 1} of {self.max_attempts}): {e}')
+                await asyncio.sleep(delay)
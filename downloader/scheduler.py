@@ -0,0 +1,70 @@
+import asyncio
+import itertools
+from settings import MAX_PARALLEL_DOWNLOADS, HASH_WORKERS, HTTP_MAX_CONNECTIONS_PER_HOST
+
+async def run_concurrently(coroutines):
+    # Like asyncio.gather, but the first failure cancels everything still running
+    # instead of leaving orphaned tasks behind.
+    tasks = [asyncio.ensure_future(coroutine) for coroutine in coroutines]
+    try:
+        await asyncio.gather(*tasks)
+    except BaseException:
+        for task in tasks:
+            task.cancel()
+        await asyncio.gather(*tasks, return_exceptions=True)
+        raise
+
+class ConnectionBudget:
+    # Shares a fixed number of connections between concurrent downloads. A
+    # segmented download asks for several at once; a small file needs one.
+    def __init__(self, total=HTTP_MAX_CONNECTIONS_PER_HOST):
+        self.total = max(1, total)
+        self.available = self.total
+        self.condition = asyncio.Condition()
+
+    async def acquire(self, count):
+        count = max(1, min(count, self.total))
+        async with self.condition:
+            await self.condition.wait_for(lambda: self.available >= count)
+            self.available -= count
+        return count
+
+    async def release(self, count):
+        async with self.condition:
+            self.available += count
+            self.condition.notify_all()
+
+async def run_scheduler(entries, check_entry, download_entry, parallel_downloads=MAX_PARALLEL_DOWNLOADS, hash_workers=HASH_WORKERS):
+    # check_entry(entry) hashes the local copy and returns None when the file is
+    # up to date, or (sort_key, job) when it must be downloaded. Jobs are queued
+    # by sort_key as soon as their check finishes, so downloads start while the
+    # remaining files are still being hashed. download_entry(job, budget) runs
+    # on one of parallel_downloads workers.
+    queue = asyncio.PriorityQueue()
+    hash_semaphore = asyncio.Semaphore(max(1, hash_workers))
+    budget = ConnectionBudget()
+    sequence = itertools.count()
+    parallel_downloads = max(1, parallel_downloads)
+
+    async def check(entry):
+        async with hash_semaphore:
+            result = await check_entry(entry)
+        if result is not None:
+            sort_key, job = result
+            await queue.put((sort_key, next(sequence), job))
+
+    async def check_all():
+        try:
+            await run_concurrently(check(entry) for entry in entries)
+        finally:
+            for _ in range(parallel_downloads):
+                await queue.put(((float('inf'),), next(sequence), None))
+
+    async def worker():
+        while True:
+            _, _, job = await queue.get()
+            if job is None:
+                return
+            await download_entry(job, budget)
+
+    await run_concurrently([check_all()] + [worker() for _ in range(parallel_downloads)])
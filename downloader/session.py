@@ -0,0 +1,90 @@
+import os
+import ssl
+import aiohttp
+from urllib.parse import urlsplit
+from urllib.request import getproxies
+from settings import (HTTP_MAX_CONNECTIONS, HTTP_MAX_CONNECTIONS_PER_HOST, HTTP_KEEPALIVE_TIMEOUT, HTTP_DNS_CACHE_TTL,
+                      HTTP_CONNECT_TIMEOUT, HTTP_READ_TIMEOUT, HTTP_TOTAL_TIMEOUT,
+                      HTTP_PROXY, HTTP_NO_PROXY, HTTP_CA_BUNDLE, HTTP_CLIENT_CERT, HTTP_CLIENT_KEY, HTTP_INSECURE)
+from logger import log_info, log_error
+
+def optional_timeout(seconds):
+    return seconds if seconds and seconds > 0 else None
+
+def create_ssl_context():
+    context = ssl.create_default_context()
+    try:
+        if HTTP_CA_BUNDLE:
+            # Added on top of the system store, so public servers keep working
+            # behind a TLS-intercepting gateway.
+            context.load_verify_locations(cafile=HTTP_CA_BUNDLE)
+            log_info(f'Loaded extra CA bundle: {HTTP_CA_BUNDLE}')
+        if HTTP_CLIENT_CERT:
+            context.load_cert_chain(certfile=HTTP_CLIENT_CERT, keyfile=HTTP_CLIENT_KEY or None)
+            log_info(f'Loaded TLS client certificate: {HTTP_CLIENT_CERT}')
+    except (OSError, ssl.SSLError) as e:
+        log_error(f"Error loading TLS settings: {e}")
+        raise Exception(f"Error loading TLS settings: {e}")
+    if HTTP_INSECURE:
+        log_error('HTTP_INSECURE is enabled: server certificates are NOT verified')
+        context.check_hostname = False
+        context.verify_mode = ssl.CERT_NONE
+    return context
+
+def no_proxy_hosts():
+    if HTTP_NO_PROXY:
+        return HTTP_NO_PROXY
+    value = os.environ.get('NO_PROXY') or os.environ.get('no_proxy') or ''
+    return [host.strip() for host in value.split(',') if host.strip()]
+
+def bypasses_proxy(url):
+    host = (urlsplit(url).hostname or '').lower()
+    for pattern in no_proxy_hosts():
+        pattern = pattern.lower().lstrip('.')
+        if pattern == '*' or host == pattern or host.endswith(f'.{pattern}'):
+            return True
+    return False
+
+def uses_socks_proxy():
+    return bool(HTTP_PROXY) and HTTP_PROXY.startswith('socks')
+
+def proxy_for_url(url):
+    # Without HTTP_PROXY the proxy comes from HTTP(S)_PROXY in the environment;
+    # either way hosts in HTTP_NO_PROXY (or NO_PROXY) are reached directly. A
+    # SOCKS proxy is applied by the connector instead.
+    if bypasses_proxy(url) or uses_socks_proxy():
+        return None
+    if HTTP_PROXY:
+        return HTTP_PROXY
+    return getproxies().get(urlsplit(url).scheme)
+
+def create_connector(ssl_context, direct=False):
+    options = {
+        'limit': HTTP_MAX_CONNECTIONS,
+        'limit_per_host': HTTP_MAX_CONNECTIONS_PER_HOST,
+        'keepalive_timeout': HTTP_KEEPALIVE_TIMEOUT,
+        'ttl_dns_cache': HTTP_DNS_CACHE_TTL,
+        'use_dns_cache': True,
+        'ssl': ssl_context,
+    }
+    if uses_socks_proxy() and not direct:
+        try:
+            from aiohttp_socks import ProxyConnector
+        except ImportError:
+            raise Exception("SOCKS proxies require the aiohttp_socks package")
+        return ProxyConnector.from_url(HTTP_PROXY, **options)
+    return aiohttp.TCPConnector(**options)
+
+def create_client_session(direct=False):
+    # direct sessions skip a SOCKS proxy, for hosts that bypass it.
+    connector = create_connector(create_ssl_context(), direct)
+    # sock_read bounds the gap between two reads rather than the whole transfer,
+    # so large files are not cut off as long as data keeps arriving.
+    timeout = aiohttp.ClientTimeout(
+        total=optional_timeout(HTTP_TOTAL_TIMEOUT),
+        connect=optional_timeout(HTTP_CONNECT_TIMEOUT),
+        sock_read=optional_timeout(HTTP_READ_TIMEOUT),
+    )
+    # Proxies from the environment are resolved by proxy_for_url, which also
+    # applies HTTP_NO_PROXY to them.
+    return aiohttp.ClientSession(connector=connector, timeout=timeout)
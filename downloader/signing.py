@@ -0,0 +1,137 @@
+import os
+import json
+import base64
+import hashlib
+from cryptography.exceptions import InvalidSignature
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey, Ed25519PublicKey
+from logger import log_info
+from downloader.manifest import InvalidManifest
+
+# Prefix of the message an old key signs to hand over trust to a new one, so a
+# rotation statement can never be mistaken for a manifest signature.
+ROTATION_CONTEXT = b'patcher-key-rotation:'
+
+class ManifestSignatureError(InvalidManifest):
+    pass
+
+def encode_public_key(public_key):
+    return base64.b64encode(public_key.public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)).decode('ascii')
+
+def decode_public_key(encoded):
+    try:
+        return Ed25519PublicKey.from_public_bytes(base64.b64decode(encoded))
+    except ValueError as e:
+        raise ManifestSignatureError(f"Invalid Ed25519 public key {encoded!r}: {e}")
+
+def key_id(encoded_public_key):
+    return hashlib.sha256(base64.b64decode(encoded_public_key)).hexdigest()[:16]
+
+def generate_private_key(path):
+    private_key = Ed25519PrivateKey.generate()
+    with open(path, 'wb') as f:
+        f.write(private_key.private_bytes(serialization.Encoding.PEM, serialization.PrivateFormat.PKCS8, serialization.NoEncryption()))
+    return encode_public_key(private_key.public_key())
+
+def load_private_key(path):
+    with open(path, 'rb') as f:
+        private_key = serialization.load_pem_private_key(f.read(), password=None)
+    if not isinstance(private_key, Ed25519PrivateKey):
+        raise ValueError(f"{path} is not an Ed25519 private key")
+    return private_key
+
+def sign_rotation(old_private_key, new_public_key):
+    # The old key vouches for the new one; clients that trust the old key will
+    # trust the new key once they have seen this statement.
+    return {
+        'key': new_public_key,
+        'signed_by': key_id(encode_public_key(old_private_key.public_key())),
+        'signature': base64.b64encode(old_private_key.sign(ROTATION_CONTEXT + base64.b64decode(new_public_key))).decode('ascii'),
+    }
+
+def sign_manifest(private_key, manifest_bytes, rotations=()):
+    public_key = encode_public_key(private_key.public_key())
+    return json.dumps({
+        'keyid': key_id(public_key),
+        'signature': base64.b64encode(private_key.sign(manifest_bytes)).decode('ascii'),
+        'rotations': list(rotations),
+    }, indent=1)
+
+class ManifestVerifier:
+    # Trusts the keys pinned in config plus any key reached from them through a
+    # chain of rotation statements. Keys learned that way are remembered in
+    # state_path, so a later manifest can drop old rotation statements. A key
+    # that has been rotated away from is remembered as superseded and never
+    # trusted again, even if it is still pinned in config.
+    def __init__(self, pinned_keys, state_path=None):
+        self.state_path = state_path
+        state = self.load_state()
+        self.superseded = set(state['superseded'])
+        self.trusted = {key_id(key): key for key in pinned_keys}
+        for key in state['keys']:
+            self.trusted.setdefault(key_id(key), key)
+        for superseded_id in self.superseded:
+            self.trusted.pop(superseded_id, None)
+
+    def load_state(self):
+        if not self.state_path or not os.path.exists(self.state_path):
+            return {'keys': [], 'superseded': []}
+        try:
+            with open(self.state_path, 'r') as f:
+                state = json.load(f)
+        except (OSError, ValueError) as e:
+            raise ManifestSignatureError(f"Could not read the trusted key state {self.state_path}: {e}")
+        if not isinstance(state, dict) or not all(isinstance(state.get(name), list) for name in ('keys', 'superseded')):
+            raise ManifestSignatureError(f"Trusted key state {self.state_path} is malformed")
+        return state
+
+    def save_state(self, state):
+        if not self.state_path:
+            return
+        os.makedirs(os.path.dirname(self.state_path), exist_ok=True)
+        temp_path = f"{self.state_path}.tmp"
+        with open(temp_path, 'w') as f:
+            json.dump({'keys': sorted(state['keys']), 'superseded': sorted(state['superseded'])}, f)
+        os.replace(temp_path, self.state_path)
+
+    def apply_rotations(self, rotations):
+        learned = []
+        # Statements may come in any order, so keep going until no new key is added.
+        progress = True
+        while progress:
+            progress = False
+            for rotation in rotations:
+                new_id = key_id(rotation['key'])
+                signer_id = rotation.get('signed_by')
+                signer = self.trusted.get(signer_id)
+                if new_id in self.trusted or new_id in self.superseded or signer is None:
+                    continue
+                try:
+                    decode_public_key(signer).verify(base64.b64decode(rotation['signature']), ROTATION_CONTEXT + base64.b64decode(rotation['key']))
+                except InvalidSignature:
+                    raise ManifestSignatureError(f"Invalid rotation statement for key {new_id}")
+                log_info(f'Trusting manifest key {new_id}, rotated from {signer_id}')
+                self.trusted[new_id] = rotation['key']
+                del self.trusted[signer_id]
+                self.superseded.add(signer_id)
+                learned.append(rotation['key'])
+                progress = True
+        if learned:
+            state = self.load_state()
+            self.save_state({'keys': state['keys'] + learned, 'superseded': self.superseded})
+
+    def verify(self, manifest_bytes, signature_bytes):
+        try:
+            document = json.loads(signature_bytes)
+            self.apply_rotations(document.get('rotations', []))
+            if document['keyid'] in self.superseded:
+                raise ManifestSignatureError(f"Manifest is signed by superseded key {document['keyid']}")
+            public_key = self.trusted.get(document['keyid'])
+            if public_key is None:
+                raise ManifestSignatureError(f"Manifest is signed by untrusted key {document['keyid']}")
+            decode_public_key(public_key).verify(base64.b64decode(document['signature']), manifest_bytes)
+        except InvalidSignature:
+            raise ManifestSignatureError("Manifest signature does not verify")
+        except (KeyError, TypeError, ValueError) as e:
+            raise ManifestSignatureError(f"Malformed manifest signature: {e}")
+        log_info(f'Manifest signature verified with key {document["keyid"]}')
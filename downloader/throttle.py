@@ -0,0 +1,34 @@
+import asyncio
+import time
+from settings import DOWNLOAD_SPEED_LIMIT, DOWNLOAD_BURST_SIZE
+
+class TokenBucket:
+    def __init__(self, rate, burst=0):
+        self.tokens = 0
+        self.last_refill = time.monotonic()
+        self.set_rate(rate, burst)
+        self.tokens = self.burst
+
+    def set_rate(self, rate, burst=0):
+        self._refill()
+        self.rate = max(0, rate)
+        self.burst = burst if burst > 0 else self.rate
+        self.tokens = min(self.tokens, self.burst)
+
+    def _refill(self):
+        now = time.monotonic()
+        if getattr(self, 'rate', 0) > 0:
+            self.tokens = min(self.burst, self.tokens + (now - self.last_refill) * self.rate)
+        self.last_refill = now
+
+    async def consume(self, amount):
+        if self.rate <= 0:
+            return
+        self._refill()
+        # Tokens may go negative: the caller reserves its share up front and sleeps
+        # off the debt, so concurrent readers are served in arrival order.
+        self.tokens -= amount
+        if self.tokens < 0:
+            await asyncio.sleep(-self.tokens / self.rate)
+
+bandwidth_limiter = TokenBucket(DOWNLOAD_SPEED_LIMIT * 1024, DOWNLOAD_BURST_SIZE * 1024)
@@ -0,0 +1,131 @@
+import os
+import json
+import shutil
+from settings import TARGET_FOLDER
+from logger import log_info, log_error
+from downloader.file_manager import STATE_DIR_NAME, fsync_path
+
+class UpdateTransaction:
+    # New files are downloaded into a staging directory and only swapped into
+    # TARGET_FOLDER once every one of them has been verified. The swap is
+    # recorded in a journal first, so if the process dies halfway through, the
+    # next run can finish it (or undo it) and the install never stays on a mix of
+    # old and new files.
+    def __init__(self, target_folder=TARGET_FOLDER, backup_store=None):
+        self.target_folder = target_folder
+        self.backup_store = backup_store
+        self.state_dir = os.path.join(target_folder, STATE_DIR_NAME)
+        self.staging_dir = os.path.join(self.state_dir, 'staging')
+        self.backup_dir = os.path.join(self.state_dir, 'backup')
+        self.journal_path = os.path.join(self.state_dir, 'transaction.json')
+
+    def target_path(self, file_name):
+        return os.path.join(self.target_folder, file_name)
+
+    def staging_path(self, file_name):
+        return os.path.join(self.staging_dir, file_name)
+
+    def backup_path(self, file_name):
+        return os.path.join(self.backup_dir, file_name)
+
+    def write_journal(self, journal):
+        os.makedirs(self.state_dir, exist_ok=True)
+        temp_path = f"{self.journal_path}.tmp"
+        with open(temp_path, 'w') as f:
+            json.dump(journal, f)
+            f.flush()
+            os.fsync(f.fileno())
+        os.replace(temp_path, self.journal_path)
+        fsync_path(self.state_dir, directory=True)
+
+    def read_journal(self):
+        try:
+            with open(self.journal_path, 'r') as f:
+                return json.load(f)
+        except FileNotFoundError:
+            return None
+        except (OSError, ValueError) as e:
+            log_error(f"Error reading transaction journal {self.journal_path}: {e}")
+            raise Exception(f"Error reading transaction journal {self.journal_path}: {e}")
+
+    def apply_file(self, entry):
+        # Idempotent: running it again after a crash picks up where it stopped.
+        target, staged, backup = self.target_path(entry['name']), self.staging_path(entry['name']), self.backup_path(entry['name'])
+        if entry.get('delete'):
+            if os.path.exists(target) and not os.path.exists(backup):
+                os.makedirs(os.path.dirname(backup), exist_ok=True)
+                os.replace(target, backup)
+            return
+        if not os.path.exists(staged):
+            return
+        if entry['existed'] and os.path.exists(target) and not os.path.exists(backup):
+            os.makedirs(os.path.dirname(backup), exist_ok=True)
+            os.replace(target, backup)
+        os.makedirs(os.path.dirname(target), exist_ok=True)
+        os.replace(staged, target)
+
+    def revert_file(self, entry):
+        target, staged, backup = self.target_path(entry['name']), self.staging_path(entry['name']), self.backup_path(entry['name'])
+        if os.path.exists(backup):
+            os.replace(backup, target)
+        elif not entry['existed'] and not entry.get('delete') and os.path.exists(target) and not os.path.exists(staged):
+            os.remove(target)
+
+    def sync_directories(self, journal):
+        for directory in {os.path.dirname(os.path.abspath(self.target_path(entry['name']))) for entry in journal['files']}:
+            fsync_path(directory, directory=True)
+
+    async def commit(self, file_names, old_hashes=None, removed_files=(), label='update'):
+        # old_hashes maps file names to the SHA-256 of the copy being replaced,
+        # when already known, so backing it up does not hash it again.
+        old_hashes = old_hashes or {}
+        entries = [{'name': name, 'existed': os.path.exists(self.target_path(name)), 'hash': old_hashes.get(name)} for name in file_names]
+        entries += [{'name': name, 'existed': True, 'hash': old_hashes.get(name), 'delete': True} for name in removed_files]
+        journal = {'state': 'committing', 'label': label, 'files': entries}
+        self.write_journal(journal)
+        log_info(f'Committing {label} of {len(entries)} file(s)')
+        try:
+            for entry in journal['files']:
+                self.apply_file(entry)
+            self.sync_directories(journal)
+        except OSError as e:
+            log_error(f'Error committing update, rolling back: {e}')
+            await self.roll_back(journal)
+            raise Exception(f"Error committing update: {e}")
+        await self.finish(journal)
+
+    async def roll_back(self, journal):
+        journal['state'] = 'rolling_back'
+        self.write_journal(journal)
+        for entry in journal['files']:
+            self.revert_file(entry)
+        self.sync_directories(journal)
+        await self.finish(journal)
+        log_info('Update rolled back to the previous version')
+
+    async def finish(self, journal):
+        if journal['state'] == 'committing' and self.backup_store is not None:
+            await self.backup_store.add_version(journal['files'], self.backup_dir, journal.get('label', 'update'))
+        shutil.rmtree(self.backup_dir, ignore_errors=True)
+        if journal['state'] == 'committing':
+            shutil.rmtree(self.staging_dir, ignore_errors=True)
+        os.remove(self.journal_path)
+        fsync_path(self.state_dir, directory=True)
+
+    async def recover(self):
+        journal = self.read_journal()
+        if journal is None:
+            return None
+        if journal.get('state') == 'committing':
+            log_info('Found an interrupted update, finishing it')
+            try:
+                for entry in journal['files']:
+                    self.apply_file(entry)
+                self.sync_directories(journal)
+                await self.finish(journal)
+                return 'committed'
+            except OSError as e:
+                log_error(f'Could not finish interrupted update, rolling back: {e}')
+        log_info('Rolling back an interrupted update')
+        await self.roll_back(journal)
+        return 'rolled back'
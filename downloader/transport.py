@@ -0,0 +1,289 @@
+import os
+import hmac
+import hashlib
+import datetime
+from contextlib import asynccontextmanager
+import aiofiles
+from urllib.parse import urlsplit, quote, unquote
+from urllib.request import url2pathname
+from yarl import URL
+from settings import S3_ENDPOINT_URL, S3_REGION, S3_ACCESS_KEY, S3_SECRET_KEY
+from logger import log_debug
+from downloader.session import create_client_session, proxy_for_url, bypasses_proxy, uses_socks_proxy
+from downloader.auth import AuthProvider, create_auth_provider
+
+CHUNK_SIZE = 65536
+
+class RemoteFileChanged(Exception):
+    pass
+
+class IncompleteTransfer(Exception):
+    pass
+
+def empty_validator():
+    return {'etag': None, 'last_modified': None}
+
+def get_validator(headers):
+    return {'etag': headers.get('ETag'), 'last_modified': headers.get('Last-Modified')}
+
+def get_content_length(headers):
+    # A Content-Length describing an encoded body says nothing about the file size.
+    if headers.get('Content-Encoding', 'identity') != 'identity' or 'Content-Length' not in headers:
+        return None
+    try:
+        return int(headers['Content-Length'])
+    except ValueError:
+        return None
+
+def parse_content_range(value):
+    # "bytes 0-0/1234" -> 1234, "bytes 0-0/*" -> None
+    if not value or '/' not in value:
+        return None
+    total = value.rsplit('/', 1)[1].strip()
+    return int(total) if total.isdigit() else None
+
+def transport_kind(url):
+    scheme = urlsplit(url).scheme.lower()
+    if scheme in ('http', 'https'):
+        return 'http'
+    if scheme == 's3':
+        return 's3'
+    # file:// URLs, plain paths and Windows drive letters ("D:\patches")
+    # all refer to the local filesystem.
+    if scheme == 'file' or len(scheme) <= 1:
+        return 'file'
+    raise ValueError(f"Unsupported URL scheme: {url}")
+
+class Transport:
+    async def stat(self, url):
+        # Returns {'size': int or None, 'ranges': bool, 'validator': {'etag', 'last_modified'}}
+        raise NotImplementedError
+
+    async def get_range(self, url, start_byte, end_byte, validator=None):
+        # Async generator yielding the bytes start_byte..end_byte (inclusive). When
+        # a validator is given and the remote no longer matches it, RemoteFileChanged
+        # is raised instead.
+        raise NotImplementedError
+        yield
+
+    async def get(self, url):
+        raise NotImplementedError
+        yield
+
+    async def read(self, url):
+        return b''.join([chunk async for chunk in self.get(url)])
+
+    async def close(self):
+        pass
+
+class HttpTransport(Transport):
+    def __init__(self, auth=None):
+        # Keyed by whether the session goes around a SOCKS proxy.
+        self._sessions = {}
+        self.auth = auth if auth is not None else create_auth_provider()
+        # Per-host results of stat probes: whether HEAD and byte ranges are
+        # supported. None means not yet known.
+        self.host_capabilities = {}
+
+    def session_for(self, url):
+        # A SOCKS proxy sits in the connector rather than in the request, so
+        # hosts that bypass it need a session of their own.
+        direct = uses_socks_proxy() and bypasses_proxy(url)
+        if direct not in self._sessions:
+            self._sessions[direct] = create_client_session(direct)
+        return self._sessions[direct]
+
+    def prepare(self, method, url, headers):
+        return url, headers
+
+    @asynccontextmanager
+    async def request(self, method, url, headers=None, **kwargs):
+        # A rejected or expired credential is refreshed and the very same request
+        # (including its Range header) is sent again, so a segment carries on
+        # from where it was instead of starting over.
+        for attempt in range(2):
+            request_url, request_headers, credential = await self.auth.authorize(self.session_for(url), method, url, dict(headers or {}))
+            request_url, request_headers = self.prepare(method, request_url, request_headers)
+            # The proxy and session follow the host actually contacted, which for
+            # S3 is the endpoint rather than the bucket.
+            target = str(request_url)
+            async with self.session_for(target).request(method, request_url, headers=request_headers, proxy=proxy_for_url(target), **kwargs) as response:
+                if response.status in (401, 403) and attempt == 0 and await self.auth.refresh(url, credential):
+                    continue
+                yield response
+                return
+
+    async def stat(self, url):
+        host = urlsplit(url).netloc
+        capabilities = self.host_capabilities.setdefault(host, {'head': None, 'ranges': None})
+        file_info = {'size': None, 'ranges': bool(capabilities['ranges']), 'validator': empty_validator()}
+
+        if capabilities['head'] is not False:
+            async with self.request('HEAD', url, allow_redirects=True) as response:
+                if response.status in (403, 405, 501):
+                    log_debug(f'Server {host} rejects HEAD requests ({response.status}), falling back to ranged GET')
+                    capabilities['head'] = False
+                else:
+                    response.raise_for_status()
+                    capabilities['head'] = True
+                    file_info['size'] = get_content_length(response.headers)
+                    file_info['validator'] = get_validator(response.headers)
+                    accept_ranges = response.headers.get('Accept-Ranges', '').lower()
+                    if accept_ranges == 'bytes':
+                        capabilities['ranges'] = True
+                    elif accept_ranges == 'none':
+                        capabilities['ranges'] = False
+            if capabilities['head'] and file_info['size'] is not None and capabilities['ranges'] is not None:
+                file_info['ranges'] = capabilities['ranges']
+                return file_info
+
+        if capabilities['ranges'] is not False:
+            async with self.request('GET', url, headers={'Range': 'bytes=0-0'}) as response:
+                if response.status != 416:
+                    response.raise_for_status()
+                if response.status in (206, 416):
+                    capabilities['ranges'] = True
+                    file_info['size'] = parse_content_range(response.headers.get('Content-Range'))
+                else:
+                    capabilities['ranges'] = False
+                    file_info['size'] = get_content_length(response.headers)
+                file_info['validator'] = get_validator(response.headers)
+                # Leave the body unread; closing the response drops the connection
+                # instead of downloading the whole file just to learn its size.
+                response.close()
+
+        file_info['ranges'] = bool(capabilities['ranges'])
+        log_debug(f'Probed {url}: size={file_info["size"]}, ranges={file_info["ranges"]}')
+        return file_info
+
+    async def get_range(self, url, start_byte, end_byte, validator=None):
+        headers = {'Range': f'bytes={start_byte}-{end_byte}'}
+        if validator:
+            headers['If-Range'] = validator['etag'] or validator['last_modified']
+        async with self.request('GET', url, headers=headers) as response:
+            response.raise_for_status()
+            if response.status != 206 and start_byte > 0:
+                if validator:
+                    raise RemoteFileChanged(f"Remote file changed since the download started: {url}")
+                raise ValueError(f"Server ignored range request for {url}")
+            async for chunk in response.content.iter_chunked(CHUNK_SIZE):
+                yield chunk
+
+    async def get(self, url):
+        async with self.request('GET', url) as response:
+            response.raise_for_status()
+            async for chunk in response.content.iter_chunked(CHUNK_SIZE):
+                yield chunk
+
+    async def close(self):
+        for session in self._sessions.values():
+            await session.close()
+        self._sessions = {}
+
+class S3Transport(HttpTransport):
+    # Talks to S3-compatible object stores (AWS, MinIO, ...) using path-style
+    # addressing: s3://bucket/key is fetched from {S3_ENDPOINT_URL}/bucket/key.
+    # Requests are signed with AWS Signature Version 4 when credentials are set.
+    def __init__(self, endpoint_url=S3_ENDPOINT_URL, region=S3_REGION, access_key=S3_ACCESS_KEY, secret_key=S3_SECRET_KEY):
+        super().__init__(auth=AuthProvider())
+        self.endpoint_url = (endpoint_url or f'https://s3.{region}.amazonaws.com').rstrip('/')
+        self.region = region
+        self.access_key = access_key
+        self.secret_key = secret_key
+
+    def prepare(self, method, url, headers):
+        parts = urlsplit(url)
+        canonical_uri = quote(f'/{parts.netloc}/{unquote(parts.path).lstrip("/")}', safe='/~')
+        endpoint = urlsplit(self.endpoint_url)
+        request_url = URL(f'{self.endpoint_url}{canonical_uri}', encoded=True)
+        if self.access_key and self.secret_key:
+            headers.update(self.sign(method, endpoint.netloc, canonical_uri))
+        return request_url, headers
+
+    def sign(self, method, host, canonical_uri):
+        now = datetime.datetime.now(datetime.timezone.utc)
+        amz_date = now.strftime('%Y%m%dT%H%M%SZ')
+        date_stamp = now.strftime('%Y%m%d')
+        payload_hash = 'UNSIGNED-PAYLOAD'
+        signed = {'host': host, 'x-amz-content-sha256': payload_hash, 'x-amz-date': amz_date}
+        signed_headers = ';'.join(sorted(signed))
+        canonical_headers = ''.join(f'{name}:{signed[name]}\n' for name in sorted(signed))
+        canonical_request = '\n'.join([method, canonical_uri, '', canonical_headers, signed_headers, payload_hash])
+        scope = f'{date_stamp}/{self.region}/s3/aws4_request'
+        string_to_sign = '\n'.join(['AWS4-HMAC-SHA256', amz_date, scope, hashlib.sha256(canonical_request.encode()).hexdigest()])
+
+        key = f'AWS4{self.secret_key}'.encode()
+        for part in (date_stamp, self.region, 's3', 'aws4_request'):
+            key = hmac.new(key, part.encode(), hashlib.sha256).digest()
+        signature = hmac.new(key, string_to_sign.encode(), hashlib.sha256).hexdigest()
+
+        return {
+            'x-amz-content-sha256': payload_hash,
+            'x-amz-date': amz_date,
+            'Authorization': f'AWS4-HMAC-SHA256 Credential={self.access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}',
+        }
+
+class LocalTransport(Transport):
+    # Serves files from the local filesystem: file:// URLs, mounted LAN shares
+    # and removable drives.
+    def path(self, url):
+        parts = urlsplit(url)
+        if parts.scheme.lower() == 'file':
+            return url2pathname(f'//{parts.netloc}{parts.path}' if parts.netloc else parts.path)
+        return url
+
+    def validator(self, path):
+        stat = os.stat(path)
+        return {'etag': None, 'last_modified': str(stat.st_mtime_ns)}
+
+    async def stat(self, url):
+        path = self.path(url)
+        return {'size': os.path.getsize(path), 'ranges': True, 'validator': self.validator(path)}
+
+    async def get_range(self, url, start_byte, end_byte, validator=None):
+        path = self.path(url)
+        if validator and self.validator(path) != validator:
+            raise RemoteFileChanged(f"Source file changed since the download started: {path}")
+        remaining = end_byte - start_byte + 1
+        async with aiofiles.open(path, 'rb') as file:
+            await file.seek(start_byte)
+            while remaining > 0:
+                chunk = await file.read(min(CHUNK_SIZE, remaining))
+                if not chunk:
+                    break
+                remaining -= len(chunk)
+                yield chunk
+
+    async def get(self, url):
+        async with aiofiles.open(self.path(url), 'rb') as file:
+            while chunk := await file.read(CHUNK_SIZE):
+                yield chunk
+
+TRANSPORT_CLASSES = {
+    'http': HttpTransport,
+    's3': S3Transport,
+    'file': LocalTransport,
+}
+
+class TransportRegistry:
+    # Hands out one transport per backend kind for the lifetime of an update run,
+    # so HTTP connections and per-host capability probes are shared between files.
+    def __init__(self):
+        self.transports = {}
+
+    def for_url(self, url):
+        kind = transport_kind(url)
+        if kind not in self.transports:
+            self.transports[kind] = TRANSPORT_CLASSES[kind]()
+        return self.transports[kind]
+
+    async def close(self):
+        for transport in self.transports.values():
+            await transport.close()
+        self.transports = {}
+
+    async def __aenter__(self):
+        return self
+
+    async def __aexit__(self, exc_type, exc, tb):
+        await self.close()
@@ -1,90 +1,560 @@
 import os
+import time
+import shutil
+import asyncio
 import aiohttp
-from settings import SERVER_URL, TARGET_FOLDER, FILELIST_URL, MULTITHREADING_THRESHOLD, PROGRESS_FILE_MAX_AGE
-from .file_manager import create_directory_if_not_exists, get_file_hash, clean_old_progress_files
-from .network import resume_download
+from functools import partial
+from settings import TARGET_FOLDER, FILELIST_URL, MANIFEST_SIGNATURE_URL, TIMESTAMP_URL, MANIFEST_PUBLIC_KEYS, MULTITHREADING_THRESHOLD, PROGRESS_FILE_MAX_AGE, EXACT_MIRROR, PROTECTED_PATHS, CHUNK_CACHE_SIZE, MAX_PARALLEL_DOWNLOADS
+from .file_manager import create_directory_if_not_exists, get_file_hash, clean_stale_download_files, fsync_path, apply_file_metadata, STATE_DIR_NAME, find_unlisted_files, remove_empty_directories, preallocate_file, temp_path_for, load_progress
+from .network import download_file, seed_download, download_compressed
+from .transport import TransportRegistry, empty_validator
+from .controller import DownloadCancelled
+from .mirrors import MirrorPool
+from .scheduler import run_scheduler, run_concurrently
+from .transaction import UpdateTransaction
+from .backups import BackupStore
+from .manifest import parse_manifest, InvalidManifest
+from .signing import ManifestVerifier, ManifestSignatureError
+from .delta import apply_delta
+from .packs import plan_pack_ranges, extract_members
+from .retry import RetryPolicy, RetriesExhausted
+from .throttle import bandwidth_limiter
+from .compression import is_supported
+from .chunks import ChunkCache, chunk_name, assemble_file
+from .blockindex import read_block_index, seed_from_local_file
+from .metadata import MetadataState, parse_timestamp, check_expiry, check_snapshot
 from logger import log_info, log_error, log_debug
 
-async def is_file_update_needed(file_name, server_file_hash):
+async def get_local_file_hash(file_name):
     local_file_path = os.path.join(TARGET_FOLDER, file_name)
     if not os.path.exists(local_file_path):
-        return True
-    local_file_hash = await get_file_hash(local_file_path)
-    return local_file_hash != server_file_hash
+        return None
+    return await get_file_hash(local_file_path)
 
-async def update_files(callback=None):
-    status_report = {'updated': [], 'skipped': [], 'failed': [], 'verification': {'verified': [], 'corrupted': []}}
-    files_to_verify = {}
+class TransferProgress:
+    # Several files download at once; their byte counts are summed so the
+    # callback sees one figure for the whole run instead of each file in turn.
+    def __init__(self, callback):
+        self.callback = callback
+        self.files = {}
+
+    def for_file(self, file_name):
+        if not self.callback:
+            return None
+
+        def report(progress, total, error=None):
+            if error:
+                self.callback(None, None, error=error)
+                return
+            self.files[file_name] = (progress, total)
+            self.callback(sum(done for done, _ in self.files.values()), sum(size for _, size in self.files.values()))
+        return report
+
+async def fetch_manifest(transports, allow_downgrade=False):
+    state_dir = os.path.join(TARGET_FOLDER, STATE_DIR_NAME)
+    verifier = ManifestVerifier(MANIFEST_PUBLIC_KEYS, os.path.join(state_dir, 'trusted_keys.json')) if MANIFEST_PUBLIC_KEYS else None
+
+    retry_policy = RetryPolicy()
+
+    async def fetch(url):
+        return await retry_policy.call(partial(transports.for_url(url).read, url), url)
+
+    async def fetch_signed(url, signature_url):
+        data = await fetch(url)
+        if verifier:
+            try:
+                signature_bytes = await fetch(signature_url)
+            except (aiohttp.ClientError, OSError, RetriesExhausted) as e:
+                raise ManifestSignatureError(f"Could not fetch the signature {signature_url}: {e}")
+            verifier.verify(data, signature_bytes)
+        return data
+
+    now = time.time()
+    state = MetadataState(os.path.join(state_dir, 'metadata.json'))
+    timestamp = None
+    if TIMESTAMP_URL:
+        timestamp = parse_timestamp(await fetch_signed(TIMESTAMP_URL, f'{TIMESTAMP_URL}.sig'))
+        check_expiry(timestamp['expires'], 'timestamp metadata', now)
+        state.check_version('timestamp_version', timestamp['version'], allow_downgrade)
+
+    manifest_bytes = await fetch_signed(FILELIST_URL, MANIFEST_SIGNATURE_URL)
+    manifest = parse_manifest(manifest_bytes.decode('utf-8'))
+    if timestamp:
+        check_snapshot(timestamp, manifest_bytes, manifest)
+    check_expiry(manifest['expires'], 'manifest', now)
+    state.check_version('manifest_version', manifest['version'], allow_downgrade)
+
+    if timestamp:
+        state.record('timestamp_version', timestamp['version'], allow_downgrade)
+    state.record('manifest_version', manifest['version'], allow_downgrade)
+    state.save()
+    return manifest
+
+async def download_from_mirrors(transports, mirror_pool, file_name, server_file_hash, destination, callback=None, controller=None, num_segments=1, hint=None, seed=None, known_info=None):
+    # hint is a (mirror, file_info) probe result for one mirror; known_info is
+    # taken from the manifest and saves the probe on every mirror.
+    await create_directory_if_not_exists(os.path.dirname(destination))
+    for mirror in mirror_pool.ranked():
+        file_url = f'{mirror.url}{file_name}'
+        try:
+            if hint and hint[0] is mirror:
+                file_info = hint[1]
+            elif known_info is not None:
+                file_info = known_info
+            else:
+                started = time.monotonic()
+                file_info = await transports.for_url(file_url).stat(file_url)
+                mirror_pool.record_latency(mirror, time.monotonic() - started)
+            file_size = file_info['size'] or 0
+            if seed is not None:
+                await seed_download(file_url, destination, server_file_hash, file_info, seed)
+
+            started = time.monotonic()
+            if num_segments > 1:
+                log_info(f'Using segmented download for {file_name} from {mirror.url}')
+            else:
+                log_info(f'Using single-stream download for {file_name} from {mirror.url}')
+            await download_file(file_url, destination, server_file_hash, num_segments=num_segments, callback=callback, controller=controller, file_info=file_info, transports=transports)
+            mirror_pool.record_success(mirror, file_size, time.monotonic() - started)
+            return mirror.url
+        except DownloadCancelled:
+            raise
+        except Exception as e:
+            log_error(f'Mirror {mirror.url} failed for {file_name}: {e}')
+            mirror_pool.record_failure(mirror)
+    return None
+
+def matching_patch(entry, local_hash):
+    return next((patch for patch in entry['patches'] if local_hash and patch['source_hash'] == local_hash), None)
+
+async def patch_from_mirrors(transports, mirror_pool, entry, patch, destination, callback=None, controller=None):
+    # Downloads the delta for the installed copy and rebuilds the new file from
+    # it. Returns the mirror url, or None so the caller can fall back to the
+    # full file.
+    delta_path = os.path.join(TARGET_FOLDER, STATE_DIR_NAME, 'deltas', patch['name'])
+    mirror_url = await download_from_mirrors(transports, mirror_pool, patch['name'], patch['hash'], delta_path, callback, controller)
+    if not mirror_url:
+        return None
+    try:
+        loop = asyncio.get_running_loop()
+        await create_directory_if_not_exists(os.path.dirname(destination))
+        actual_hash = await loop.run_in_executor(None, apply_delta, os.path.join(TARGET_FOLDER, entry['name']), delta_path, destination)
+    except Exception as e:
+        # The file helpers report OSError as a plain Exception; any failure here
+        # just means falling back to the full file.
+        log_error(f"Could not apply delta {patch['name']}: {e}")
+        return None
+    finally:
+        if os.path.exists(delta_path):
+            os.remove(delta_path)
+    if actual_hash != entry['hash']:
+        log_error(f"Delta {patch['name']} produced {actual_hash} instead of {entry['hash']}")
+        os.remove(destination)
+        return None
+    return mirror_url
+
+async def download_object_from_mirrors(transports, mirror_pool, entry, destination, callback=None, controller=None):
+    # Downloads the compressed copy of the file, decompressing as it arrives.
+    compressed_object = entry['object']
+    await create_directory_if_not_exists(os.path.dirname(destination))
+    for mirror in mirror_pool.ranked():
+        object_url = f"{mirror.url}{compressed_object['name']}"
+        try:
+            started = time.monotonic()
+            log_info(f"Using {compressed_object['compression']} download for {entry['name']} from {mirror.url}")
+            await download_compressed(object_url, destination, entry['hash'], entry['size'], compressed_object, callback, controller=controller, transports=transports)
+            mirror_pool.record_success(mirror, compressed_object['size'] or 0, time.monotonic() - started)
+            return mirror.url
+        except DownloadCancelled:
+            raise
+        except Exception as e:
+            log_error(f"Mirror {mirror.url} failed for {compressed_object['name']}: {e}")
+            mirror_pool.record_failure(mirror)
+    return None
+
+async def seed_from_local_copy(transports, mirror_pool, entry, destination, callback=None, controller=None):
+    # zsync-style: fetches the block index of the new file, copies every block
+    # the installed copy already has into the download's temporary file and
+    # returns the ranges filled, so only the missing ones are downloaded.
+    local_path = os.path.join(TARGET_FOLDER, entry['name'])
+    staging_path = temp_path_for(destination)
+    if not os.path.exists(local_path) or await load_progress(staging_path) is not None:
+        return None
+    index = entry['block_index']
+    index_path = os.path.join(TARGET_FOLDER, STATE_DIR_NAME, 'blockindex', index['name'])
+    if not await download_from_mirrors(transports, mirror_pool, index['name'], index['hash'], index_path, callback, controller):
+        return None
+    try:
+        loop = asyncio.get_running_loop()
+        _, file_size, _ = await loop.run_in_executor(None, read_block_index, index_path)
+        await create_directory_if_not_exists(os.path.dirname(staging_path))
+        await preallocate_file(staging_path, file_size)
+        should_stop = (lambda: controller.cancelled) if controller else None
+        ranges = await loop.run_in_executor(None, seed_from_local_file, local_path, index_path, staging_path, should_stop)
+    except Exception as e:
+        log_error(f"Could not use the block index {index['name']}: {e}")
+        return None
+    finally:
+        if os.path.exists(index_path):
+            os.remove(index_path)
+    reused = sum(end - start for start, end in ranges)
+    log_info(f"Reusing {reused} of {file_size} bytes of the installed {entry['name']}")
+    return ranges
+
+async def assemble_from_chunks(transports, mirror_pool, chunk_cache, entry, destination, callback=None, controller=None, workers=1):
+    # Downloads the chunks of the file that are not cached yet and rebuilds it
+    # from the cache. Returns the mirror urls used (empty if every chunk was
+    # cached), or None if the file could not be rebuilt.
+    pending = iter(entry['chunks'])
+    mirror_urls = set()
+    missing = []
+    assembled = 0
+
+    async def fetch(chunk_hash, size, chunk_path):
+        # The manifest already gives the chunk size, so there is no need to probe
+        # the mirror; small chunks are fetched in one request without a journal.
+        known_info = {'size': size, 'ranges': size > MULTITHREADING_THRESHOLD, 'validator': empty_validator()}
+        mirror_url = await download_from_mirrors(transports, mirror_pool, chunk_name(chunk_hash), chunk_hash, chunk_path, controller=controller, known_info=known_info)
+        if mirror_url:
+            mirror_urls.add(mirror_url)
+        return mirror_url is not None
+
+    async def worker():
+        nonlocal assembled
+        for chunk_hash, size in pending:
+            if not await chunk_cache.ensure(chunk_hash, partial(fetch, chunk_hash, size)):
+                missing.append(chunk_hash)
+                return
+            assembled += size
+            if callback:
+                callback(assembled, entry['size'] or 0)
+
+    await run_concurrently(worker() for _ in range(workers))
+    if missing:
+        log_error(f"Could not download chunk {missing[0]} of {entry['name']}")
+        return None
+    try:
+        loop = asyncio.get_running_loop()
+        await create_directory_if_not_exists(os.path.dirname(destination))
+        actual_hash = await loop.run_in_executor(None, assemble_file, [chunk_cache.path(chunk_hash) for chunk_hash, _ in entry['chunks']], destination)
+    except Exception as e:
+        log_error(f"Could not rebuild {entry['name']} from chunks: {e}")
+        return None
+    if actual_hash != entry['hash']:
+        log_error(f"Chunks of {entry['name']} produced {actual_hash} instead of {entry['hash']}")
+        os.remove(destination)
+        return None
+    return mirror_urls
+
+async def extract_from_packs(transports, mirror_pool, pack, members, destination_for, controller=None, retry_policy=None):
+    # Fetches the ranges of pack that hold the needed members and writes them
+    # out. Returns {name: mirror_url} for the members whose hash matched; the
+    # caller downloads the others on their own.
+    retry_policy = retry_policy or RetryPolicy()
+    extracted = {}
+
+    async def on_data(size):
+        if controller:
+            await controller.checkpoint()
+        await bandwidth_limiter.consume(size)
+
+    for start, end, range_members in plan_pack_ranges(members, pack['size']):
+        for mirror in mirror_pool.ranked():
+            pack_url = f"{mirror.url}{pack['name']}"
+            transport = transports.for_url(pack_url)
+
+            async def attempt():
+                return await extract_members(transport.get_range(pack_url, start, end), start, range_members, destination_for, on_data)
+
+            try:
+                started = time.monotonic()
+                hashes = await retry_policy.call(attempt, f'bytes {start}-{end} of {pack_url}')
+                mirror_pool.record_success(mirror, end - start + 1, time.monotonic() - started)
+            except DownloadCancelled:
+                raise
+            except Exception as e:
+                log_error(f'Mirror {mirror.url} failed for {pack["name"]}: {e}')
+                mirror_pool.record_failure(mirror)
+                continue
+            for entry in range_members:
+                if hashes.get(entry['name']) == entry['hash']:
+                    extracted[entry['name']] = mirror.url
+                else:
+                    log_error(f"Checksum mismatch for {entry['name']} in {pack['name']}")
+            break
+    return extracted
+
+async def update_files(callback=None, controller=None, allow_downgrade=False, file_callback=None):
+    # callback(bytes, total) follows the bytes of all downloads together;
+    # file_callback(files, total) counts the files dealt with so far.
+    status_report = {'release': None, 'updated': [], 'patched': [], 'skipped': [], 'removed': [], 'failed': [], 'verification': {'verified': [], 'corrupted': []}, 'mirrors': {}, 'cancelled': False, 'transaction': None, 'error': None}
+    transaction = UpdateTransaction(backup_store=BackupStore())
+    chunk_cache = ChunkCache(os.path.join(TARGET_FOLDER, STATE_DIR_NAME, 'chunks'), CHUNK_CACHE_SIZE * 1048576)
+    staged_files = []
+    # Hashes of the installed copies, reused when they are moved into the backups.
+    old_hashes = {}
+    pack_members = []
     try:
-        async with aiohttp.ClientSession() as session:  
+        async with TransportRegistry() as transports:
             await create_directory_if_not_exists(TARGET_FOLDER)
-            async with session.get(FILELIST_URL) as filelist_response:
-                filelist_response.raise_for_status()
-                filelist_text = await filelist_response.text()
-                filelist = filelist_text.split('\n')
+            recovered = await transaction.recover()
+            if recovered:
+                log_info(f'Interrupted update {recovered}')
+            # Downloads are only ever staged inside the state directory; files
+            # elsewhere in the install belong to the user and are left alone.
+            await clean_stale_download_files(os.path.join(TARGET_FOLDER, STATE_DIR_NAME), PROGRESS_FILE_MAX_AGE)
+            manifest = await fetch_manifest(transports, allow_downgrade)
+            status_report['release'] = manifest['release']
+            if manifest['release']:
+                log_info(f"Manifest for release {manifest['release']} (format version {manifest['format_version']})")
+
+            mirror_pool = MirrorPool()
+            for mirror in manifest['mirrors']:
+                mirror_pool.add(mirror['url'], mirror['weight'])
+            entries = manifest['files']
+            status_report['failed'].extend(manifest['invalid'])
+
+            total_files = len(entries)
+            progress = TransferProgress(callback)
+            processed_files = 0
 
-            filelist = [line.strip() for line in filelist if line.strip()]
+            def file_processed():
+                nonlocal processed_files
+                processed_files += 1
+                if file_callback:
+                    file_callback(processed_files, total_files)
 
-            total_files = len(filelist)
-            updated_files = 0
+            async def queue_entry(entry):
+                return (-entry['priority'], entry['size'] or 0), (entry, None)
 
-            for file_entry in filelist:
-                if ',' in file_entry:
-                    file_name, server_file_hash = file_entry.split(',')
-                    log_debug(f'Processing file: {file_name}')
-                    file_url = f'{SERVER_URL}{file_name}'
+            async def check_entry(entry):
+                if controller:
+                    await controller.checkpoint()
+                file_name = entry['name']
+                log_debug(f'Processing file: {file_name}')
+                local_path = os.path.join(TARGET_FOLDER, file_name)
+                try:
+                    # A size mismatch already proves the file changed, no need to hash
+                    # it, unless the hash is needed to pick a delta.
+                    if entry['size'] is not None and not entry['patches'] and os.path.exists(local_path) and os.path.getsize(local_path) != entry['size']:
+                        local_hash = None
+                    else:
+                        local_hash = await get_local_file_hash(file_name)
+                except Exception as e:
+                    log_error(f'Error checking file {file_name}: {e}')
+                    status_report['failed'].append(file_name)
+                    file_processed()
+                    return None
+                old_hashes[file_name] = local_hash
+                if local_hash == entry['hash']:
+                    apply_file_metadata(local_path, entry['mode'])
+                    log_info(f'File is up-to-date, skipping: {file_name}')
+                    status_report['skipped'].append(file_name)
+                    file_processed()
+                    return None
+                staged_path = transaction.staging_path(file_name)
+                if os.path.exists(staged_path) and await get_file_hash(staged_path) == entry['hash']:
+                    apply_file_metadata(staged_path, entry['mode'], entry['mtime'])
+                    log_info(f'Reusing file staged by an earlier run: {file_name}')
+                    staged_files.append(file_name)
+                    status_report['updated'].append(file_name)
+                    status_report['verification']['verified'].append(file_name)
+                    file_processed()
+                    return None
+                patch = matching_patch(entry, local_hash)
+                if patch:
+                    return (-entry['priority'], patch['size'] if patch['size'] is not None else float('inf')), (entry, None)
+                if entry['pack']:
+                    # Fetched together with the other members of its pack once
+                    # every file has been checked.
+                    pack_members.append(entry)
+                    return None
+                if entry['chunks'] is not None or entry['size'] is not None:
+                    return (-entry['priority'], entry['size'] if entry['size'] is not None else float('inf')), (entry, None)
+                # Without a size in the manifest, probe the preferred mirror now so the queue can put small files
+                # first; the result is reused when the download starts.
+                mirror = mirror_pool.ranked()[0]
+                file_url = f'{mirror.url}{file_name}'
+                try:
+                    started = time.monotonic()
+                    file_info = await transports.for_url(file_url).stat(file_url)
+                    mirror_pool.record_latency(mirror, time.monotonic() - started)
+                    hint = (mirror, file_info)
+                    size = file_info['size'] if file_info['size'] is not None else float('inf')
+                except Exception as e:
+                    log_debug(f'Could not probe {file_name} on {mirror.url}: {e}')
+                    hint, size = None, entry['size'] if entry['size'] is not None else float('inf')
+                return (-entry['priority'], size), (entry, hint)
 
+            async def download_entry(job, budget):
+                entry, hint = job
+                file_name = entry['name']
+                file_progress = progress.for_file(file_name)
+                mirror_url = None
+                patch = matching_patch(entry, old_hashes.get(file_name))
+                if patch:
+                    await budget.acquire(1)
+                    try:
+                        mirror_url = await patch_from_mirrors(transports, mirror_pool, entry, patch, transaction.staging_path(file_name), file_progress, controller)
+                    finally:
+                        await budget.release(1)
+                    if mirror_url:
+                        log_info(f"File patched from its previous version: {file_name}")
+                        status_report['patched'].append(file_name)
+                    else:
+                        log_info(f'Delta for {file_name} failed, downloading the full file')
+                if not mirror_url and entry['chunks'] is not None:
+                    workers = await budget.acquire(controller.num_segments if controller else 4)
+                    try:
+                        mirror_urls = await assemble_from_chunks(transports, mirror_pool, chunk_cache, entry, transaction.staging_path(file_name), file_progress, controller, workers)
+                    finally:
+                        await budget.release(workers)
+                    if mirror_urls is not None:
+                        mirror_url = ', '.join(sorted(mirror_urls)) or 'chunk cache'
+                        log_info(f'File rebuilt from chunks: {file_name}')
+                    else:
+                        log_info(f'Chunks of {file_name} failed, downloading the full file')
+                seed = None
+                if not mirror_url and entry['block_index']:
+                    seed = await seed_from_local_copy(transports, mirror_pool, entry, transaction.staging_path(file_name), file_progress, controller)
+                # Once local blocks are reused only the missing ranges of the plain
+                # file are fetched; otherwise the compressed copy is the cheaper one.
+                if not mirror_url and not seed and entry['object'] and is_supported(entry['object']['compression']):
+                    await budget.acquire(1)
+                    try:
+                        mirror_url = await download_object_from_mirrors(transports, mirror_pool, entry, transaction.staging_path(file_name), file_progress, controller)
+                    finally:
+                        await budget.release(1)
+                    if not mirror_url:
+                        log_info(f'Compressed copy of {file_name} failed, downloading the full file')
+                if not mirror_url:
+                    size = (hint[1]['size'] or 0) if hint else (entry['size'] or 0)
+                    num_segments = (controller.num_segments if controller else 4) if size > MULTITHREADING_THRESHOLD else 1
+                    # A small file listed with its size is fetched in one request
+                    # without probing the mirror first; larger ones are probed for
+                    # range support and a validator to resume against.
+                    known_info = None
+                    if not hint and not seed and entry['size'] is not None and entry['size'] <= MULTITHREADING_THRESHOLD:
+                        known_info = {'size': entry['size'], 'ranges': False, 'validator': empty_validator()}
+                    num_segments = await budget.acquire(num_segments)
                     try:
-                        async with session.head(file_url) as head_response:
-                            file_size = int(head_response.headers.get('Content-Length', 0))
+                        mirror_url = await download_from_mirrors(transports, mirror_pool, file_name, entry['hash'], transaction.staging_path(file_name), file_progress, controller, num_segments, hint, seed, known_info)
+                    finally:
+                        await budget.release(num_segments)
+                if mirror_url:
+                    file_staged(entry, mirror_url)
+                else:
+                    log_error(f'Error updating file {file_name}: all mirrors failed')
+                    status_report['failed'].append(file_name)
+                file_processed()
+
+            def file_staged(entry, mirror_url):
+                file_name = entry['name']
+                apply_file_metadata(transaction.staging_path(file_name), entry['mode'], entry['mtime'])
+                log_info(f'File staged: {file_name} (from {mirror_url})')
+                staged_files.append(file_name)
+                status_report['updated'].append(file_name)
+                status_report['mirrors'][file_name] = mirror_url
+                # The hash was computed from the bytes as they were written, so
+                # the file does not need to be read back to verify it.
+                status_report['verification']['verified'].append(file_name)
+
+            async def download_packs():
+                pending_packs = iter(manifest['packs'])
+                unpacked = []
 
-                        if await is_file_update_needed(file_name, server_file_hash):
-                            if file_size > MULTITHREADING_THRESHOLD:
-                                log_info(f'Using multithreaded download for {file_name}')
-                                await resume_download(file_url, os.path.join(TARGET_FOLDER, file_name), server_file_hash, num_threads=4, callback=callback)
+                async def worker():
+                    for pack in pending_packs:
+                        members = [entry for entry in pack_members if entry['pack']['name'] == pack['name']]
+                        if not members:
+                            continue
+                        log_info(f"Fetching {len(members)} file(s) from {pack['name']}")
+                        extracted = await extract_from_packs(transports, mirror_pool, pack, members, lambda entry: transaction.staging_path(entry['name']), controller)
+                        for entry in members:
+                            if entry['name'] in extracted:
+                                file_staged(entry, extracted[entry['name']])
+                                file_processed()
                             else:
-                                log_info(f'Using single-threaded download for {file_name}')
-                                await resume_download(file_url, os.path.join(TARGET_FOLDER, file_name), server_file_hash, callback=callback)
-
-                            log_info(f'File updated: {file_name}')
-                            status_report['updated'].append(file_name)
-                            files_to_verify[file_name] = server_file_hash
-                        else:
-                            log_info(f'File is up-to-date, skipping: {file_name}')
-                            status_report['skipped'].append(file_name)
-                        updated_files += 1
-                        if callback:
-                            callback(updated_files, total_files)
-                    except aiohttp.ClientError as e:
-                        log_error(f'Error updating file {file_name}: {e}')
-                        status_report['failed'].append(file_name)
-                else:
-                    log_error(f'Invalid file entry format: {file_entry}')
-                    status_report['failed'].append(file_entry)
+                                unpacked.append(entry)
+
+                await run_concurrently(worker() for _ in range(max(1, MAX_PARALLEL_DOWNLOADS)))
+                return unpacked
+
+            await run_scheduler(entries, check_entry, download_entry)
+            if pack_members:
+                # Whatever could not be taken from a pack is downloaded on its own.
+                unpacked = await download_packs()
+                await run_scheduler(unpacked, queue_entry, download_entry)
 
-            verification_report = await verify_file_integrity(files_to_verify, callback=callback)
-            status_report['verification'] = verification_report
-            await clean_old_progress_files(TARGET_FOLDER, PROGRESS_FILE_MAX_AGE)
-    except aiohttp.ClientError as e:
+            if status_report['failed']:
+                log_error(f'{len(status_report["failed"])} file(s) failed, keeping the current version; staged files are kept for the next run')
+                status_report['transaction'] = 'aborted'
+            else:
+                # Files dropped from the release are removed in the same transaction,
+                # so they are backed up and restored by a rollback like any other.
+                removed_files = find_unlisted_files(TARGET_FOLDER, [entry['name'] for entry in entries], PROTECTED_PATHS) if EXACT_MIRROR else []
+                if staged_files or removed_files:
+                    label = f"update to {manifest['release']}" if manifest['release'] else 'update'
+                    await transaction.commit(staged_files, old_hashes, removed_files, label=label)
+                    status_report['transaction'] = 'committed'
+                    status_report['removed'] = removed_files
+                    for file_name in removed_files:
+                        log_info(f'Removed file not in the manifest: {file_name}')
+                if EXACT_MIRROR:
+                    status_report['removed'] += remove_empty_directories(TARGET_FOLDER, PROTECTED_PATHS)
+            chunk_cache.prune()
+    except DownloadCancelled:
+        log_info('Update cancelled by user')
+        status_report['cancelled'] = True
+        status_report['transaction'] = 'aborted'
+    except (aiohttp.ClientError, OSError, RetriesExhausted) as e:
         log_error(f'Error fetching file list: {e}')
+        status_report['error'] = f'Error fetching file list: {e}'
+    except InvalidManifest as e:
+        # Covers bad signatures and stale or rolled-back metadata as well.
+        log_error(f'Error reading file list: {e}')
+        status_report['transaction'] = 'aborted'
+        status_report['error'] = f'Error reading file list: {e}'
+
+    if status_report['transaction'] != 'committed':
+        # Nothing was installed: report the downloaded files as staged instead.
+        status_report['staged'] = status_report['updated']
+        status_report['updated'] = []
 
     return status_report
 
-async def verify_file_integrity(files_to_verify, callback=None):
-    verification_report = {'verified': [], 'corrupted': []}
-    total_files = len(files_to_verify)
-    processed_files = 0
-    for file_name, expected_checksum in files_to_verify.items():
-        local_file_path = os.path.join(TARGET_FOLDER, file_name)
-        if os.path.exists(local_file_path):
-            local_file_hash = await get_file_hash(local_file_path)
-            if local_file_hash == expected_checksum:
-                log_info(f'File integrity verified for {file_name}')
-                verification_report['verified'].append(file_name)
-            else:
-                log_error(f'File integrity check failed for {file_name}')
-                verification_report['corrupted'].append(file_name)
-        processed_files += 1
-        if callback:
-            callback(processed_files, total_files)
-    return verification_report
\ No newline at end of file
+def list_versions():
+    return BackupStore().load_versions()
+
+async def rollback_to_version(version_id=None):
+    # Restores the files as they were before update version_id (the latest one
+    # by default). The rollback goes through the same transaction as an update,
+    # so it is itself backed up and can be undone.
+    backup_store = BackupStore()
+    transaction = UpdateTransaction(backup_store=backup_store)
+    await create_directory_if_not_exists(TARGET_FOLDER)
+    recovered = await transaction.recover()
+    if recovered:
+        log_info(f'Interrupted update {recovered}')
+    versions = backup_store.load_versions()
+    if not versions:
+        raise Exception("No previous version is available to roll back to")
+    version_id = version_id or versions[-1]['id']
+    plan = backup_store.restore_plan(version_id)
+
+    loop = asyncio.get_running_loop()
+    restored, removed = [], []
+    for file_name, file_hash in sorted(plan.items()):
+        if file_hash is None:
+            if os.path.exists(transaction.target_path(file_name)):
+                removed.append(file_name)
+            continue
+        staged_path = transaction.staging_path(file_name)
+        await create_directory_if_not_exists(os.path.dirname(staged_path))
+        await loop.run_in_executor(None, shutil.copyfile, backup_store.object_path(file_hash), staged_path)
+        await loop.run_in_executor(None, fsync_path, staged_path)
+        restored.append(file_name)
+
+    await transaction.commit(restored, removed_files=removed, label=f'rollback to version {version_id}')
+    log_info(f'Rolled back to version {version_id}: {len(restored)} file(s) restored, {len(removed)} removed')
+    return {'version': version_id, 'restored': restored, 'removed': removed}
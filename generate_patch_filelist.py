@@ -1,41 +1,206 @@
 import os
-from downloader.file_manager import create_directory_if_not_exists, get_file_hash
+import json
+import time
+import stat
+import argparse
+from downloader.file_manager import create_directory_if_not_exists, get_file_hash, STATE_DIR_NAME
+from downloader.manifest import new_entry, RESERVED_SUFFIXES, serialize_manifest, serialize_legacy_manifest
+from downloader.delta import generate_delta
+from downloader.chunks import store_chunks, CHUNK_DIR
+from downloader.packs import build_packs, PACK_DIR
+from downloader.compression import compress_file, COMPRESSION_SUFFIXES
+from downloader.blockindex import write_block_index, BLOCK_INDEX_SUFFIX
+from downloader.metadata import build_timestamp, format_expiry
+from downloader.signing import generate_private_key, load_private_key, sign_manifest, sign_rotation, encode_public_key
 from logger import log_info, log_error
 
 TARGET_FOLDER = 'path/to/your/target/folder'
-OUTPUT_FILE = 'patcher.txt'
+OUTPUT_FILE = 'patcher.json'
 
-async def generate_filelist(target_folder):
+PATCH_DIR = 'patches'
+COMPRESSED_DIR = 'compressed'
+
+async def generate_filelist(target_folder, excluded=()):
+    # excluded holds the generator's own output files, which are rewritten after
+    # the walk and must not be listed with stale hashes.
+    excluded = {os.path.abspath(path) for path in excluded if path}
     filelist = []
-    for root, _, files in os.walk(target_folder):
-        for file in files:
+    for root, dirs, files in os.walk(target_folder):
+        dirs[:] = sorted(d for d in dirs if d != STATE_DIR_NAME and not (os.path.samefile(root, target_folder) and d in (PATCH_DIR, CHUNK_DIR, PACK_DIR, COMPRESSED_DIR)))
+        for file in sorted(files):
+            if file.endswith(BLOCK_INDEX_SUFFIX) and file[:-len(BLOCK_INDEX_SUFFIX)] in files:
+                continue
+            if file.endswith(RESERVED_SUFFIXES):
+                log_info(f'Skipping {file}: the name is reserved for the patcher')
+                continue
             filepath = os.path.join(root, file)
+            if os.path.abspath(filepath) in excluded:
+                continue
             if os.path.abspath(filepath).startswith(os.path.abspath(target_folder)):
                 file_hash = await get_file_hash(filepath)
-                relative_path = os.path.relpath(filepath, target_folder)
-                filelist.append(f"{relative_path},{file_hash}")
+                relative_path = os.path.relpath(filepath, target_folder).replace(os.sep, '/')
+                file_stat = os.stat(filepath)
+                mode = stat.S_IMODE(file_stat.st_mode) & 0o777 if os.name != 'nt' else None
+                filelist.append(new_entry(relative_path, file_hash, size=file_stat.st_size, mode=mode, mtime=int(file_stat.st_mtime)))
     return filelist
 
-def save_filelist(filelist, output_file):
+def load_rotations(path):
+    if not path or not os.path.exists(path):
+        return []
+    with open(path, 'r') as f:
+        return json.load(f)
+
+async def generate_patches(filelist, target_folder, previous_folders):
+    # Deltas are written to PATCH_DIR inside the release folder and published
+    # with it; each one is only kept if it is smaller than the file itself.
+    for entry in filelist:
+        for previous_folder in previous_folders:
+            source_path = os.path.join(previous_folder, entry['name'])
+            if not os.path.isfile(source_path):
+                continue
+            source_hash = await get_file_hash(source_path)
+            if source_hash == entry['hash'] or any(patch['source_hash'] == source_hash for patch in entry['patches']):
+                continue
+            patch_name = f"{PATCH_DIR}/{entry['name']}.{source_hash[:16]}.delta"
+            patch_path = os.path.join(target_folder, patch_name)
+            await create_directory_if_not_exists(os.path.dirname(patch_path))
+            generate_delta(source_path, os.path.join(target_folder, entry['name']), patch_path)
+            patch_size = os.path.getsize(patch_path)
+            if patch_size >= entry['size']:
+                os.remove(patch_path)
+                continue
+            entry['patches'].append({'source_hash': source_hash, 'name': patch_name, 'hash': await get_file_hash(patch_path), 'size': patch_size})
+            log_info(f"Delta for {entry['name']} from {previous_folder}: {patch_size} of {entry['size']} bytes")
+
+async def generate_block_indexes(filelist, target_folder, min_size):
+    # The index is published next to the file it describes.
+    for entry in filelist:
+        if entry['size'] < min_size:
+            continue
+        index_name = f"{entry['name']}{BLOCK_INDEX_SUFFIX}"
+        index_path = os.path.join(target_folder, index_name)
+        write_block_index(os.path.join(target_folder, entry['name']), index_path)
+        entry['block_index'] = {'name': index_name, 'hash': await get_file_hash(index_path), 'size': os.path.getsize(index_path)}
+
+def generate_chunks(filelist, target_folder, min_size):
+    # Chunks go to CHUNK_DIR inside the release folder, named by their hash, so
+    # data shared by several files is stored and downloaded once.
+    for entry in filelist:
+        if entry['size'] >= min_size:
+            entry['chunks'] = store_chunks(os.path.join(target_folder, entry['name']), target_folder)
+
+async def generate_compressed_objects(filelist, target_folder, compression, min_size):
+    # Compressed copies go to COMPRESSED_DIR; the plain files stay published
+    # for clients that cannot decompress them.
+    for entry in filelist:
+        if entry['size'] < min_size:
+            continue
+        object_name = f"{COMPRESSED_DIR}/{entry['name']}{COMPRESSION_SUFFIXES[compression]}"
+        object_path = os.path.join(target_folder, object_name)
+        await create_directory_if_not_exists(os.path.dirname(object_path))
+        compress_file(os.path.join(target_folder, entry['name']), object_path, compression)
+        object_size = os.path.getsize(object_path)
+        if object_size >= entry['size']:
+            os.remove(object_path)
+            continue
+        entry['object'] = {'name': object_name, 'compression': compression, 'hash': await get_file_hash(object_path), 'size': object_size}
+
+def write_signed(path, data, sign_key=None, rotations_file=None):
+    # The detached signature covers the exact bytes written.
+    with open(path, 'wb') as f:
+        f.write(data)
+    log_info(f'{path} has been saved')
+    if sign_key:
+        with open(f'{path}.sig', 'w') as f:
+            f.write(sign_manifest(load_private_key(sign_key), data, load_rotations(rotations_file)))
+        log_info(f'Signature has been saved to {path}.sig')
+
+def expiry_in(days):
+    return format_expiry(time.time() + days * 86400) if days else None
+
+def save_filelist(filelist, output_file, release=None, legacy=False, sign_key=None, rotations_file=None, version=None, expires=None, packs=None):
     try:
-        with open(output_file, 'w') as f:
-            for file_entry in filelist:
-                f.write(f"{file_entry}\n")
-        log_info(f'File list has been saved to {output_file}')
+        manifest = serialize_legacy_manifest(filelist) if legacy else serialize_manifest(filelist, release, version=version, expires=expires, packs=packs)
+        write_signed(output_file, manifest.encode('utf-8'), sign_key, rotations_file)
     except IOError as e:
         log_error(f'Error saving file list to {output_file}: {e}')
         raise Exception(f"Error saving file list to {output_file}: {e}")
 
+def save_timestamp(timestamp_file, manifest_file, expires, sign_key=None, rotations_file=None):
+    # Re-run with --timestamp-only more often than the timestamp expires, so
+    # clients can tell a fresh server from one frozen on an old manifest.
+    with open(manifest_file, 'rb') as f:
+        manifest_bytes = f.read()
+    try:
+        manifest_version = json.loads(manifest_bytes).get('version')
+    except ValueError:
+        manifest_version = None
+    timestamp = build_timestamp(manifest_bytes, manifest_version, int(time.time()), expires)
+    write_signed(timestamp_file, timestamp.encode('utf-8'), sign_key, rotations_file)
+
+def parse_arguments():
+    parser = argparse.ArgumentParser(description='Generate the patcher file list')
+    parser.add_argument('target_folder', nargs='?', default=TARGET_FOLDER)
+    parser.add_argument('-o', '--output', default=OUTPUT_FILE)
+    parser.add_argument('--release', help='release version recorded in the manifest')
+    parser.add_argument('--legacy', action='store_true', help='write the old name,hash format')
+    parser.add_argument('--previous', action='append', default=[], metavar='DIR', help='earlier release to build delta patches from (repeatable)')
+    parser.add_argument('--block-index-min-size', type=int, default=0, metavar='BYTES', help='write block indexes for files at least this large, 0 = none')
+    parser.add_argument('--chunk-threshold', type=int, default=0, metavar='BYTES', help='publish files at least this large as content-defined chunks, 0 = off')
+    parser.add_argument('--pack-threshold', type=int, default=0, metavar='BYTES', help='bundle files smaller than this into pack files, 0 = off')
+    parser.add_argument('--pack-size', type=int, default=16777216, metavar='BYTES', help='largest pack file')
+    parser.add_argument('--compress', choices=sorted(COMPRESSION_SUFFIXES), help='also publish compressed copies of the files')
+    parser.add_argument('--compress-min-size', type=int, default=4096, metavar='BYTES', help='smallest file to compress')
+    parser.add_argument('--manifest-version', type=int, default=None, help='increasing manifest version (default: the current time)')
+    parser.add_argument('--expires-in', type=float, default=30, metavar='DAYS', help='days until the manifest expires, 0 = never')
+    parser.add_argument('--timestamp', metavar='FILE', help='also write timestamp metadata for the manifest to FILE')
+    parser.add_argument('--timestamp-expires-in', type=float, default=1, metavar='DAYS', help='days until the timestamp metadata expires')
+    parser.add_argument('--timestamp-only', action='store_true', help='only refresh the timestamp metadata of an existing manifest')
+    parser.add_argument('--sign-key', metavar='KEY', help='Ed25519 private key (PEM) used to sign the manifest')
+    parser.add_argument('--rotations', metavar='FILE', default='rotations.json', help='key rotation statements published with the signature')
+    parser.add_argument('--generate-key', metavar='KEY', help='create a new Ed25519 private key and print its public key')
+    parser.add_argument('--rotate', nargs=2, metavar=('OLD_KEY', 'NEW_KEY'), help='sign NEW_KEY with OLD_KEY and add it to the rotations file')
+    return parser.parse_args()
+
+def rotate_key(old_key_path, new_key_path, rotations_file):
+    new_public_key = encode_public_key(load_private_key(new_key_path).public_key())
+    rotations = load_rotations(rotations_file)
+    rotations.append(sign_rotation(load_private_key(old_key_path), new_public_key))
+    with open(rotations_file, 'w') as f:
+        json.dump(rotations, f, indent=1)
+    print(f"Key rotation saved to {rotations_file}; sign the next manifests with {new_key_path}.")
+
 async def main():
+    args = parse_arguments()
+    if args.generate_key:
+        print(f"Public key for MANIFEST_PUBLIC_KEYS: {generate_private_key(args.generate_key)}")
+        return
+    if args.rotate:
+        rotate_key(args.rotate[0], args.rotate[1], args.rotations)
+        return
     try:
-        await create_directory_if_not_exists(TARGET_FOLDER)
-        filelist = await generate_filelist(TARGET_FOLDER)
-        save_filelist(filelist, OUTPUT_FILE)
-        print("File list generated successfully.")
+        if not args.timestamp_only:
+            await create_directory_if_not_exists(args.target_folder)
+            outputs = [args.output, f'{args.output}.sig', args.timestamp, args.timestamp and f'{args.timestamp}.sig', args.rotations, args.sign_key]
+            filelist = await generate_filelist(args.target_folder, outputs)
+            await generate_patches(filelist, args.target_folder, args.previous)
+            if args.chunk_threshold:
+                generate_chunks(filelist, args.target_folder, args.chunk_threshold)
+            if args.block_index_min_size:
+                await generate_block_indexes(filelist, args.target_folder, args.block_index_min_size)
+            if args.compress:
+                await generate_compressed_objects(filelist, args.target_folder, args.compress, args.compress_min_size)
+            packs = await build_packs(filelist, args.target_folder, args.pack_threshold, args.pack_size) if args.pack_threshold else []
+            version = args.manifest_version if args.manifest_version is not None else int(time.time())
+            save_filelist(filelist, args.output, args.release, args.legacy, args.sign_key, args.rotations, version, expiry_in(args.expires_in), packs)
+            print("File list generated successfully.")
+        if args.timestamp:
+            save_timestamp(args.timestamp, args.output, expiry_in(args.timestamp_expires_in), args.sign_key, args.rotations)
+            print("Timestamp metadata generated successfully.")
     except Exception as e:
         print(f'Error generating file list: {e}')
         log_error(f'Error generating file list: {e}')
 
 if __name__ == "__main__":
     import asyncio
-    asyncio.run(main())
\ No newline at end of file
+    asyncio.run(main())
@@ -1,25 +1,34 @@
 import sys
-from PyQt5.QtWidgets import QApplication, QMainWindow, QPushButton, QVBoxLayout, QWidget, QProgressBar, QMessageBox, QDesktopWidget
-from PyQt5.QtCore import QThread, pyqtSignal
+from PyQt5.QtWidgets import QApplication, QMainWindow, QPushButton, QVBoxLayout, QHBoxLayout, QWidget, QProgressBar, QMessageBox, QDesktopWidget, QSlider, QLabel, QInputDialog, QSpinBox
+from PyQt5.QtCore import QThread, pyqtSignal, Qt
+import time
 import asyncio
-from downloader.updater import update_files
+from settings import DOWNLOAD_SPEED_LIMIT, HTTP_MAX_CONNECTIONS_PER_HOST
+from downloader.updater import update_files, list_versions, rollback_to_version
+from downloader.controller import TransferController
 from logger import log_info, log_error
 
 class PatcherThread(QThread):
-    progress_updated = pyqtSignal(int, int)
+    # Byte totals of a whole update can pass the range of a Qt int.
+    progress_updated = pyqtSignal(object, object)
+    files_updated = pyqtSignal(int, int)
     update_status = pyqtSignal(dict)
     error_occurred = pyqtSignal(str)
 
-    def __init__(self):
+    def __init__(self, controller, allow_downgrade=False):
         super().__init__()
+        self.controller = controller
+        self.allow_downgrade = allow_downgrade
         self.success = False
         self.error = ""
+        self.status_report = {}
 
     def run(self):
         loop = asyncio.new_event_loop()
         asyncio.set_event_loop(loop)
         try:
-            status_report = loop.run_until_complete(update_files(callback=self.update_progress_bar))
+            status_report = loop.run_until_complete(update_files(callback=self.update_progress_bar, controller=self.controller, allow_downgrade=self.allow_downgrade, file_callback=self.files_updated.emit))
+            self.status_report = status_report
             self.update_status.emit(status_report)
             self.success = True
         except Exception as e:
@@ -35,23 +44,86 @@ class PatcherThread(QThread):
         else:
             self.progress_updated.emit(progress, total)
 
+class RollbackThread(QThread):
+    rollback_done = pyqtSignal(dict)
+    error_occurred = pyqtSignal(str)
+
+    def __init__(self, version_id):
+        super().__init__()
+        self.version_id = version_id
+
+    def run(self):
+        loop = asyncio.new_event_loop()
+        asyncio.set_event_loop(loop)
+        try:
+            self.rollback_done.emit(loop.run_until_complete(rollback_to_version(self.version_id)))
+        except Exception as e:
+            self.error_occurred.emit(str(e))
+        finally:
+            loop.close()
+
 class PatcherGUI(QMainWindow):
-    def __init__(self):
+    MAX_SPEED_LIMIT = 10240  # KB/s
+
+    def __init__(self, allow_downgrade=False):
         super().__init__()
+        self.allow_downgrade = allow_downgrade
         self.setWindowTitle('Patcher GUI')
         self.setGeometry(100, 100, 400, 200)
+        self.controller = None
         self.initUI()
 
     def initUI(self):
         self.download_button = QPushButton('Download Updates', self)
         self.download_button.clicked.connect(self.start_patcher_thread)
         
+        self.rollback_button = QPushButton('Roll Back', self)
+        self.rollback_button.clicked.connect(self.start_rollback_thread)
+
+        self.pause_button = QPushButton('Pause', self)
+        self.pause_button.setEnabled(False)
+        self.pause_button.clicked.connect(self.toggle_pause)
+
+        self.cancel_button = QPushButton('Cancel', self)
+        self.cancel_button.setEnabled(False)
+        self.cancel_button.clicked.connect(self.cancel_patcher_thread)
+
         self.progress_bar = QProgressBar(self)
         self.progress_bar.setMaximum(100)
-        
+        self.files_label = QLabel(self)
+
+        self.speed_label = QLabel(self)
+        self.speed_slider = QSlider(Qt.Horizontal, self)
+        self.speed_slider.setRange(0, self.MAX_SPEED_LIMIT)
+        self.speed_slider.setSingleStep(64)
+        self.speed_slider.setPageStep(1024)
+        self.speed_slider.valueChanged.connect(self.change_speed_limit)
+        self.speed_slider.setValue(min(DOWNLOAD_SPEED_LIMIT, self.MAX_SPEED_LIMIT))
+        self.update_speed_label(self.speed_slider.value())
+
+        self.connections_label = QLabel('Connections per file:', self)
+        self.connections_spinbox = QSpinBox(self)
+        self.connections_spinbox.setRange(1, HTTP_MAX_CONNECTIONS_PER_HOST)
+        self.connections_spinbox.setValue(min(4, HTTP_MAX_CONNECTIONS_PER_HOST))
+        self.connections_spinbox.valueChanged.connect(self.change_connections)
+
+        connections_layout = QHBoxLayout()
+        connections_layout.addWidget(self.connections_label)
+        connections_layout.addWidget(self.connections_spinbox)
+
+        controls_layout = QHBoxLayout()
+        controls_layout.addWidget(self.pause_button)
+        controls_layout.addWidget(self.cancel_button)
+
         layout = QVBoxLayout()
         layout.addWidget(self.download_button)
+        layout.addWidget(self.rollback_button)
+        layout.addLayout(controls_layout)
         layout.addWidget(self.progress_bar)
+        layout.addWidget(self.files_label)
+        layout.addWidget(self.speed_label)
+        layout.addWidget(self.speed_slider)
+        layout.addLayout(connections_layout)
 
         container = QWidget()
         container.setLayout(layout)
@@ -59,10 +131,42 @@ class PatcherGUI(QMainWindow):
         self.center()
 
     def update_progress_bar(self, progress, total):
-        self.progress_bar.setMaximum(total)
-        self.progress_bar.setValue(progress)
+        self.progress_bar.setValue(progress * 100 // total if total else 0)
+
+    def update_files_label(self, files, total):
+        self.files_label.setText(f'Files: {files} of {total}')
+
+    def update_speed_label(self, value):
+        self.speed_label.setText(f'Speed limit: {value} KB/s' if value > 0 else 'Speed limit: Unlimited')
+
+    def change_speed_limit(self, value):
+        self.update_speed_label(value)
+        if self.controller:
+            self.controller.set_speed_limit(value)
+
+    def change_connections(self, value):
+        if self.controller:
+            self.controller.set_connections(value)
+
+    def toggle_pause(self):
+        if self.controller.paused:
+            self.controller.resume()
+            self.pause_button.setText('Pause')
+            log_info('Download resumed by user.')
+        else:
+            self.controller.pause()
+            self.pause_button.setText('Resume')
+            log_info('Download paused by user.')
+
+    def cancel_patcher_thread(self):
+        self.cancel_button.setEnabled(False)
+        self.pause_button.setEnabled(False)
+        self.controller.cancel()
 
     def display_update_status(self, status_report):
+        if status_report.get('error'):
+            # Nothing was checked; patcher_finished reports the error.
+            return
         updated_files = '\n'.join(status_report['updated'])
         skipped_files = '\n'.join(status_report['skipped'])
         failed_files = '\n'.join(status_report['failed'])
@@ -70,36 +174,103 @@ class PatcherGUI(QMainWindow):
         corrupted_files = '\n'.join(status_report['verification']['corrupted'])
 
         message = f"Updated:\n{updated_files}\n\nSkipped:\n{skipped_files}\n\nFailed:\n{failed_files}"
+        if status_report.get('removed'):
+            removed_files = '\n'.join(status_report['removed'])
+            message += f"\n\nRemoved:\n{removed_files}"
+        if status_report.get('transaction') == 'aborted' and not status_report.get('cancelled'):
+            staged_files = '\n'.join(status_report.get('staged', []))
+            message = f"Some files could not be updated, so no changes were applied.\n\nDownloaded (applied on the next successful update):\n{staged_files}\n\n{message}"
+        if status_report.get('cancelled'):
+            message = f"Update was cancelled. Interrupted downloads will resume next time.\n\n{message}"
         if verified_files or corrupted_files:
             message += f"\n\nVerified:\n{verified_files}\n\nCorrupted:\n{corrupted_files}"
         QMessageBox.information(self, 'Update Status', message)
 
     def patcher_finished(self):
-        if self.patcher_thread.success:
+        # success only means update_files returned; whether anything was applied
+        # is up to the transaction.
+        transaction = self.patcher_thread.status_report.get('transaction')
+        error = self.patcher_thread.status_report.get('error')
+        if self.patcher_thread.success and self.controller.cancelled:
+            log_info('Update cancelled.')
+        elif self.patcher_thread.success and error:
+            QMessageBox.critical(self, 'Error', error)
+            log_error(f'Error during patching: {error}')
+        elif self.patcher_thread.success and transaction == 'aborted':
+            QMessageBox.warning(self, 'Update Failed', 'Some files could not be updated, so no changes were applied.')
+            log_error('Update aborted, no changes were applied.')
+        elif self.patcher_thread.success:
             QMessageBox.information(self, 'Success', 'Files have been updated successfully.')
             log_info('Files updated successfully.')
         else:
             QMessageBox.critical(self, 'Error', self.patcher_thread.error)
             log_error(f'Error during patching: {self.patcher_thread.error}')
         self.download_button.setEnabled(True)
+        self.rollback_button.setEnabled(True)
+        self.pause_button.setEnabled(False)
+        self.pause_button.setText('Pause')
+        self.cancel_button.setEnabled(False)
         self.progress_bar.setValue(0)
 
     def start_patcher_thread(self):
         self.download_button.setEnabled(False)
-        self.patcher_thread = PatcherThread()
+        self.rollback_button.setEnabled(False)
+        self.pause_button.setEnabled(True)
+        self.cancel_button.setEnabled(True)
+        self.controller = TransferController(self.connections_spinbox.value())
+        self.controller.set_speed_limit(self.speed_slider.value())
+        self.patcher_thread = PatcherThread(self.controller, self.allow_downgrade)
         self.patcher_thread.progress_updated.connect(self.update_progress_bar)
+        self.patcher_thread.files_updated.connect(self.update_files_label)
         self.patcher_thread.update_status.connect(self.display_update_status)
         self.patcher_thread.finished.connect(self.patcher_finished)
         self.patcher_thread.start()
 
+    def start_rollback_thread(self):
+        try:
+            versions = list(reversed(list_versions()))
+        except Exception as e:
+            QMessageBox.critical(self, 'Error', str(e))
+            return
+        if not versions:
+            QMessageBox.information(self, 'Roll Back', 'No previous version is available.')
+            return
+        choices = [
+            f"{version['id']}: before {version['label']} on {time.strftime('%Y-%m-%d %H:%M', time.localtime(version['created']))}"
+            for version in versions
+        ]
+        choice, accepted = QInputDialog.getItem(self, 'Roll Back', 'Restore the files as they were:', choices, 0, False)
+        if not accepted:
+            return
+        self.download_button.setEnabled(False)
+        self.rollback_button.setEnabled(False)
+        self.rollback_thread = RollbackThread(versions[choices.index(choice)]['id'])
+        self.rollback_thread.rollback_done.connect(self.rollback_finished)
+        self.rollback_thread.error_occurred.connect(self.rollback_failed)
+        self.rollback_thread.start()
+
+    def rollback_finished(self, result):
+        restored_files = '\n'.join(result['restored'])
+        removed_files = '\n'.join(result['removed'])
+        QMessageBox.information(self, 'Roll Back', f"Rolled back to version {result['version']}.\n\nRestored:\n{restored_files}\n\nRemoved:\n{removed_files}")
+        log_info(f"Rolled back to version {result['version']}.")
+        self.download_button.setEnabled(True)
+        self.rollback_button.setEnabled(True)
+
+    def rollback_failed(self, error):
+        QMessageBox.critical(self, 'Error', error)
+        log_error(f'Error during rollback: {error}')
+        self.download_button.setEnabled(True)
+        self.rollback_button.setEnabled(True)
+
     def center(self):
         qr = self.frameGeometry()
         cp = QDesktopWidget().availableGeometry().center()
         qr.moveCenter(cp)
         self.move(qr.topLeft())
 
-def run():
+def run(allow_downgrade=False):
     app = QApplication(sys.argv)
-    window = PatcherGUI()
+    window = PatcherGUI(allow_downgrade)
     window.show()
     sys.exit(app.exec_())
\ No newline at end of file
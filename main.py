@@ -1,8 +1,38 @@
-from gui import run
+import time
+import asyncio
+import argparse
 from utils import handle_shutdown_signals
 from logger import log_info
 
+def parse_arguments():
+    parser = argparse.ArgumentParser(description='Patcher')
+    parser.add_argument('--list-versions', action='store_true', help='list the previous versions available for rollback')
+    parser.add_argument('--allow-downgrade', action='store_true', help='accept a manifest older than the one already installed')
+    parser.add_argument('--rollback', nargs='?', type=int, const=0, metavar='VERSION', help='restore the files as they were before VERSION (default: the latest update)')
+    return parser.parse_args()
+
+def print_versions():
+    from downloader.updater import list_versions
+    versions = list_versions()
+    if not versions:
+        print('No previous versions available.')
+    for version in reversed(versions):
+        created = time.strftime('%Y-%m-%d %H:%M:%S', time.localtime(version['created']))
+        print(f"{version['id']}\t{created}\tbefore {version['label']} ({len(version['files'])} file(s))")
+
+def rollback(version_id):
+    from downloader.updater import rollback_to_version
+    result = asyncio.run(rollback_to_version(version_id or None))
+    print(f"Rolled back to version {result['version']}: {len(result['restored'])} file(s) restored, {len(result['removed'])} removed.")
+
 if __name__ == '__main__':
+    args = parse_arguments()
     log_info("Patcher application starting...")
     handle_shutdown_signals()
-    run()
\ No newline at end of file
+    if args.list_versions:
+        print_versions()
+    elif args.rollback is not None:
+        rollback(args.rollback)
+    else:
+        from gui import run
+        run(allow_downgrade=args.allow_downgrade)
@@ -9,6 +9,50 @@ config = load_config()
 SERVER_URL = config['SERVER_URL']
 TARGET_FOLDER = config['TARGET_FOLDER']
 FILELIST_URL = config['FILELIST_URL']
+MANIFEST_SIGNATURE_URL = config.get('MANIFEST_SIGNATURE_URL') or f'{FILELIST_URL}.sig'
+TIMESTAMP_URL = config.get('TIMESTAMP_URL') or None
+MANIFEST_PUBLIC_KEYS = config.get('MANIFEST_PUBLIC_KEYS') or []
 DOWNLOAD_SPEED_LIMIT = config['DOWNLOAD_SPEED_LIMIT']
+DOWNLOAD_BURST_SIZE = config.get('DOWNLOAD_BURST_SIZE', 0)
 MULTITHREADING_THRESHOLD = config['MULTITHREADING_THRESHOLD']
-PROGRESS_FILE_MAX_AGE = config.get('PROGRESS_FILE_MAX_AGE', 86400)
\ No newline at end of file
+PROGRESS_FILE_MAX_AGE = config.get('PROGRESS_FILE_MAX_AGE', 86400)
+SEGMENT_SIZE = config.get('SEGMENT_SIZE', 4194304)
+MAX_PARALLEL_DOWNLOADS = config.get('MAX_PARALLEL_DOWNLOADS', 4)
+HASH_WORKERS = config.get('HASH_WORKERS', 4)
+EXACT_MIRROR = config.get('EXACT_MIRROR', False)
+PROTECTED_PATHS = config.get('PROTECTED_PATHS') or []
+BACKUP_RETENTION_COUNT = config.get('BACKUP_RETENTION_COUNT', 3)
+BACKUP_RETENTION_SIZE = config.get('BACKUP_RETENTION_SIZE', 0)
+CHUNK_CACHE_SIZE = config.get('CHUNK_CACHE_SIZE', 1024)
+RETRY_MAX_ATTEMPTS = config.get('RETRY_MAX_ATTEMPTS', 3)
+RETRY_BASE_DELAY = config.get('RETRY_BASE_DELAY', 1)
+RETRY_MAX_DELAY = config.get('RETRY_MAX_DELAY', 120)
+RETRY_AFTER_MAX = config.get('RETRY_AFTER_MAX', 300)
+MIRRORS = config.get('MIRRORS') or [{'url': SERVER_URL, 'weight': 1}]
+MIRROR_MAX_FAILURES = config.get('MIRROR_MAX_FAILURES', 3)
+MIRROR_BLACKLIST_TIME = config.get('MIRROR_BLACKLIST_TIME', 300)
+HTTP_MAX_CONNECTIONS = config.get('HTTP_MAX_CONNECTIONS', 100)
+HTTP_MAX_CONNECTIONS_PER_HOST = config.get('HTTP_MAX_CONNECTIONS_PER_HOST', 8)
+HTTP_KEEPALIVE_TIMEOUT = config.get('HTTP_KEEPALIVE_TIMEOUT', 30)
+HTTP_DNS_CACHE_TTL = config.get('HTTP_DNS_CACHE_TTL', 300)
+HTTP_CONNECT_TIMEOUT = config.get('HTTP_CONNECT_TIMEOUT', 10)
+HTTP_READ_TIMEOUT = config.get('HTTP_READ_TIMEOUT', 30)
+HTTP_TOTAL_TIMEOUT = config.get('HTTP_TOTAL_TIMEOUT', 0)
+HTTP_PROXY = config.get('HTTP_PROXY') or None
+HTTP_NO_PROXY = config.get('HTTP_NO_PROXY') or []
+HTTP_CA_BUNDLE = config.get('HTTP_CA_BUNDLE') or None
+HTTP_CLIENT_CERT = config.get('HTTP_CLIENT_CERT') or None
+HTTP_CLIENT_KEY = config.get('HTTP_CLIENT_KEY') or None
+HTTP_INSECURE = config.get('HTTP_INSECURE', False)
+AUTH_TYPE = config.get('AUTH_TYPE', 'none')
+AUTH_TOKEN = config.get('AUTH_TOKEN') or None
+AUTH_USERNAME = config.get('AUTH_USERNAME') or None
+AUTH_PASSWORD = config.get('AUTH_PASSWORD') or None
+AUTH_CREDENTIALS_FILE = config.get('AUTH_CREDENTIALS_FILE') or None
+AUTH_TOKEN_URL = config.get('AUTH_TOKEN_URL') or None
+AUTH_SIGNED_URLS = config.get('AUTH_SIGNED_URLS', False)
+AUTH_HOSTS = config.get('AUTH_HOSTS') or []
+S3_ENDPOINT_URL = config.get('S3_ENDPOINT_URL')
+S3_REGION = config.get('S3_REGION', 'us-east-1')
+S3_ACCESS_KEY = config.get('S3_ACCESS_KEY')
+S3_SECRET_KEY = config.get('S3_SECRET_KEY')
\ No newline at end of file